@@ -7,6 +7,7 @@ import { v4 as uuidv4 } from 'uuid';
 import { createServer } from 'http';
 import fetch from 'node-fetch';
 import fs from 'fs';
+import crypto from 'crypto';
 
 // Debug logging to file
 const DEBUG_LOG = '/tmp/captcha-backend-debug.log';
@@ -30,6 +31,9 @@ const HCAPTCHA_SITE_KEY = process.env.HCAPTCHA_SITE_KEY || '10000000-ffff-ffff-f
 const HCAPTCHA_SECRET = process.env.HCAPTCHA_SECRET || '0x0000000000000000000000000000000000000000'; // Test secret
 const HCAPTCHA_VERIFY_URL = 'https://api.hcaptcha.com/siteverify';
 
+// Shared with the worker (RESPONSE_SECRET in its secrets profile) to sign wait and verify responses
+const RESPONSE_SECRET = process.env.RESPONSE_SECRET || '';
+
 // Allowed origins for CORS
 const ALLOWED_ORIGINS = process.env.ALLOWED_ORIGINS
     ? process.env.ALLOWED_ORIGINS.split(',')
@@ -68,6 +72,27 @@ app.use(session({
 // In-memory storage
 const pendingChallenges = new Map();
 const wsConnections = new Map(); // session_id -> WebSocket
+const challengesByIdempotencyKey = new Map(); // Idempotency-Key -> challenge_id
+const challengesByNonce = new Map(); // nonce -> { challenge_id, created_at }
+const finishedChallenges = new Map(); // challenge_id -> { result, finished_at }, replayed to re-executed workers
+
+// How long a nonce maps to its challenge, and a finished result is kept for re-executions
+const NONCE_TTL_MS = 10 * 60 * 1000;
+
+// Move a challenge out of the pending set, keeping its result for re-executed workers
+function finishChallenge(challenge_id, result) {
+    pendingChallenges.delete(challenge_id);
+    finishedChallenges.set(challenge_id, { result, finished_at: Date.now() });
+}
+
+// Send JSON with an X-Signature header (hex HMAC-SHA256 of the exact body) when signing is enabled
+function sendSignedJson(res, payload) {
+    const body = JSON.stringify(payload);
+    if (RESPONSE_SECRET) {
+        res.set('X-Signature', crypto.createHmac('sha256', RESPONSE_SECRET).update(body).digest('hex'));
+    }
+    res.type('application/json').send(body);
+}
 
 // HTTP server
 const server = createServer(app);
@@ -134,29 +159,67 @@ async function verifyHCaptchaToken(token, remoteip) {
 
 // API: Create CAPTCHA challenge
 app.post('/api/captcha/challenge', (req, res) => {
-    const { session_id, buyer, amount, transaction_hash } = req.body;
+    const { session_id, sale_id, buyer, amount, transaction_hash, expected_tokens, client_ip, user_agent } = req.body;
+    // 'image' (default) or 'audio' for buyers who can't solve a visual CAPTCHA
+    const challenge_type = req.body.challenge_type === 'audio' ? 'audio' : 'image';
 
     if (!session_id) {
         return res.status(400).json({ error: 'session_id is required' });
     }
 
+    // Retried creation with the same key returns the existing challenge
+    const idempotencyKey = req.get('Idempotency-Key');
+    const existingId = idempotencyKey && challengesByIdempotencyKey.get(idempotencyKey);
+    if (existingId && pendingChallenges.has(existingId)) {
+        console.log(`🔁 Reusing challenge ${existingId} for idempotency key ${idempotencyKey}`);
+        return res.json({ challenge_id: existingId });
+    }
+
+    // The worker derives the nonce from session, buyer and amount, so an
+    // OutLayer re-execution repeats it, possibly after the challenge finished.
+    // It gets the original challenge (and, from the wait endpoint, its original
+    // result) instead of a fresh one that could be solved again
+    const { nonce } = req.body;
+    const byNonce = nonce && challengesByNonce.get(nonce);
+    if (byNonce && Date.now() - byNonce.created_at <= NONCE_TTL_MS) {
+        console.log(`🔁 Reusing challenge ${byNonce.challenge_id} for repeated nonce ${nonce}`);
+        return res.json({ challenge_id: byNonce.challenge_id });
+    }
+
     // Create challenge
     const challenge_id = uuidv4();
 
     const challenge = {
         session_id,
+        // Set by contracts that tag their sale; lets one backend serve several sales
+        sale_id,
         buyer,
         amount,
         transaction_hash,
+        // Echoed with the result so the worker can tie it to this purchase
+        expected_tokens,
+        challenge_type,
+        // Optional client fingerprint for risk scoring, absent unless the front-end supplied it
+        client_ip,
+        user_agent,
         status: 'pending',
         verified: false,
         created_at: Date.now()
     };
 
     pendingChallenges.set(challenge_id, challenge);
+    if (nonce) {
+        challengesByNonce.set(nonce, { challenge_id, created_at: challenge.created_at });
+    }
+    if (idempotencyKey) {
+        challengesByIdempotencyKey.set(idempotencyKey, challenge_id);
+    }
 
-    console.log(`📝 hCaptcha challenge created: ${challenge_id} for session ${session_id}`);
+    console.log(`📝 hCaptcha ${challenge_type} challenge created: ${challenge_id} for session ${session_id}${sale_id ? ` (sale ${sale_id})` : ''}`);
     console.log(`   Buyer: ${buyer}, Amount: ${amount}, TX: ${transaction_hash || 'unknown'}`);
+    if (client_ip || user_agent) {
+        console.log(`   Client: ${client_ip || 'unknown'} (${user_agent || 'unknown agent'})`);
+    }
 
     // Send to user's browser via WebSocket
     const ws = wsConnections.get(session_id);
@@ -172,6 +235,7 @@ app.post('/api/captcha/challenge', (req, res) => {
             amount_yocto: amount,
             transaction_hash: transaction_hash || 'unknown',
             captcha_type: 'hcaptcha',
+            challenge_type,
             site_key: HCAPTCHA_SITE_KEY
         }));
         console.log(`   ✅ Sent to WebSocket (amount: ${amountInNear} NEAR, tx: ${transaction_hash})`);
@@ -191,6 +255,11 @@ app.get('/api/captcha/wait/:challenge_id', (req, res) => {
     const challenge = pendingChallenges.get(challenge_id);
 
     if (!challenge) {
+        // A re-executed worker asking again after the result was delivered
+        const finished = finishedChallenges.get(challenge_id);
+        if (finished) {
+            return sendSignedJson(res, finished.result);
+        }
         return res.status(404).json({ error: 'Challenge not found' });
     }
 
@@ -204,25 +273,25 @@ app.get('/api/captcha/wait/:challenge_id', (req, res) => {
         // Check if challenge was solved
         if (challenge.status === 'solved') {
             console.log(`✅ Worker received result for ${challenge_id}: verified=${challenge.verified}`);
-            pendingChallenges.delete(challenge_id);
-            return res.json({
+            const result = {
                 status: 'solved',
-                verified: challenge.verified
-            });
+                verified: challenge.verified,
+                expected_tokens: challenge.expected_tokens
+            };
+            finishChallenge(challenge_id, result);
+            return sendSignedJson(res, result);
         }
 
         // Check if challenge timed out (60 seconds from creation)
         if (challengeAge > 60) {
-            pendingChallenges.delete(challenge_id);
-            return res.json({
-                status: 'timeout',
-                verified: false
-            });
+            const result = { status: 'timeout', verified: false };
+            finishChallenge(challenge_id, result);
+            return sendSignedJson(res, result);
         }
 
         // Check if long-polling timed out
         if (elapsed >= maxTimeout) {
-            return res.json({
+            return sendSignedJson(res, {
                 status: 'pending',
                 verified: false
             });
@@ -262,6 +331,40 @@ app.post('/api/captcha/solve/:challenge_id', async (req, res) => {
     res.json({ verified });
 });
 
+// API: Verify an answer the front-end collected up front (submitted by the worker)
+app.post('/api/captcha/verify/:challenge_id', async (req, res) => {
+    const { challenge_id } = req.params;
+    const { answer } = req.body;
+
+    const challenge = pendingChallenges.get(challenge_id);
+
+    if (!challenge) {
+        // A re-executed worker submitting again; the hCaptcha token is single-use
+        const finished = finishedChallenges.get(challenge_id);
+        if (finished) {
+            return sendSignedJson(res, finished.result);
+        }
+        return res.status(404).json({ error: 'Challenge not found' });
+    }
+
+    if (challenge.status !== 'pending') {
+        return res.status(400).json({ error: 'Challenge already solved' });
+    }
+
+    const verified = await verifyHCaptchaToken(answer, challenge.client_ip);
+    const result = {
+        status: 'solved',
+        verified,
+        // Echoed like the wait result, so the worker can tie the verdict to this purchase
+        expected_tokens: challenge.expected_tokens
+    };
+    finishChallenge(challenge_id, result);
+
+    console.log(`✅ Pre-collected answer for ${challenge_id} verified: ${verified ? 'PASS ✓' : 'FAIL ✗'}`);
+
+    sendSignedJson(res, result);
+});
+
 // API: Health check
 app.get('/health', (req, res) => {
     res.json({
@@ -279,11 +382,29 @@ setInterval(() => {
 
     for (const [id, challenge] of pendingChallenges.entries()) {
         if (now - challenge.created_at > 60000) { // 60 seconds
-            pendingChallenges.delete(id);
+            finishChallenge(id, { status: 'timeout', verified: false });
             cleaned++;
         }
     }
 
+    for (const [nonce, entry] of challengesByNonce.entries()) {
+        if (now - entry.created_at > NONCE_TTL_MS) {
+            challengesByNonce.delete(nonce);
+        }
+    }
+
+    for (const [id, finished] of finishedChallenges.entries()) {
+        if (now - finished.finished_at > NONCE_TTL_MS) {
+            finishedChallenges.delete(id);
+        }
+    }
+
+    for (const [key, id] of challengesByIdempotencyKey.entries()) {
+        if (!pendingChallenges.has(id)) {
+            challengesByIdempotencyKey.delete(key);
+        }
+    }
+
     if (cleaned > 0) {
         console.log(`🧹 Cleaned up ${cleaned} old challenges`);
     }
@@ -0,0 +1,2703 @@
+//! CAPTCHA verification flow run by the captcha-ark OutLayer worker
+//!
+//! All HTTP goes through [`HttpClient`], so the flow can be exercised with a
+//! mock client; the worker binary uses [`WasiHttpClient`].
+
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize)]
+pub struct Input {
+    pub session_id: String,
+    pub buyer: String,
+    pub amount: String,
+    pub launchpad_url: LaunchpadUrls,
+    /// Skip all network calls and return a canned result (CI / onboarding only,
+    /// must never be set in production input)
+    #[serde(default)]
+    pub dry_run: Option<bool>,
+    /// Seconds to wait for a connection (default 10, max 10)
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds to wait for a response body once headers arrive (default 10, max 30)
+    #[serde(default)]
+    pub read_timeout_secs: Option<u64>,
+    /// Delay between re-polls while the challenge is still pending
+    /// (default 2000, clamped to 250..=10000)
+    #[serde(default)]
+    pub poll_interval_ms: Option<u64>,
+    /// Upper bound on wait requests (default: as many as fit the run budget)
+    #[serde(default)]
+    pub max_polls: Option<u32>,
+    /// Answer collected by the frontend; when set it is submitted directly
+    /// instead of long-polling for the user to solve
+    #[serde(default)]
+    pub answer: Option<String>,
+    /// Launchpads tried in order after `launchpad_url` if it is unreachable
+    /// or returns a 5xx
+    #[serde(default)]
+    pub fallback_launchpad_urls: Vec<String>,
+    /// `Idempotency-Key` sent when creating the challenge
+    /// (default: derived from `session_id`)
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Extra CAPTCHA difficulty requested by the contract for an account that
+    /// keeps failing, forwarded to the launchpad (absent = normal)
+    #[serde(default)]
+    pub difficulty: Option<u8>,
+    /// Sale the purchase belongs to, forwarded so the launchpad can scope
+    /// challenges per sale (absent in older contracts)
+    #[serde(default)]
+    pub sale_id: Option<String>,
+    /// Kind of CAPTCHA the launchpad should issue: "image" (default) or
+    /// "audio" for buyers who can't solve a visual challenge
+    #[serde(default)]
+    pub challenge_type: Option<String>,
+    /// Buyer's IP as seen by the launchpad front-end, forwarded for risk scoring
+    #[serde(default)]
+    pub client_ip: Option<String>,
+    /// Buyer's browser user agent, forwarded for risk scoring
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Tokens the purchase yields, computed on-chain; sent to the launchpad,
+    /// which must echo it back with a solved result (absent = not checked)
+    #[serde(default)]
+    pub expected_tokens: Option<String>,
+    /// Path of the challenge endpoint (default "/api/captcha/challenge")
+    #[serde(default)]
+    pub challenge_path: Option<String>,
+    /// Path of the wait endpoint, with a `{challenge_id}` placeholder
+    /// (default "/api/captcha/wait/{challenge_id}")
+    #[serde(default)]
+    pub wait_path_template: Option<String>,
+    /// Path the proof-of-work nonce is submitted to, with a `{challenge_id}`
+    /// placeholder (default "/api/captcha/pow/{challenge_id}")
+    #[serde(default)]
+    pub pow_path_template: Option<String>,
+    /// Path a pre-collected `answer` is submitted to, with a `{challenge_id}`
+    /// placeholder (default "/api/captcha/verify/{challenge_id}")
+    #[serde(default)]
+    pub verify_path_template: Option<String>,
+    /// "error", "info" or "debug" (default "info"); unknown values mean "info"
+    #[serde(default)]
+    pub log_level: Option<String>,
+}
+
+/// One launchpad URL, or several tried in order until one accepts the challenge
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum LaunchpadUrls {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl LaunchpadUrls {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            LaunchpadUrls::Single(url) => std::slice::from_ref(url),
+            LaunchpadUrls::Multiple(urls) => urls,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Output {
+    pub verified: bool,
+    /// Single authoritative result: "verified" or the error type
+    pub status: String,
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_type: Option<CaptchaErrorType>,
+    /// Milliseconds from worker start until the result was known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub solve_latency_ms: Option<u64>,
+    /// HTTP requests sent to the launchpad, including redirect hops
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    /// Launchpad URL that served the challenge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+    /// Kind of CAPTCHA requested: "image" or "audio"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub challenge_type: Option<String>,
+    /// Hex HMAC-SHA256 over `session_id|buyer|amount|verified|receipt_timestamp`,
+    /// keyed with `RECEIPT_SECRET` (absent when no secret is configured)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+    /// Unix seconds covered by `receipt`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt_timestamp: Option<u64>,
+    /// Package version of this worker, with `+{commit}` when built with
+    /// `CAPTCHA_ARK_COMMIT` set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker_version: Option<String>,
+}
+
+impl Output {
+    /// Output for a run rejected before verification started
+    pub fn failure(session_id: &str, error: &VerifyError) -> Self {
+        Self {
+            verified: false,
+            status: error.error_type.as_str().to_string(),
+            session_id: session_id.to_string(),
+            error: Some(format!("Verification failed: {}", error)),
+            error_type: Some(error.error_type),
+            solve_latency_ms: None,
+            attempts: None,
+            served_by: None,
+            challenge_type: None,
+            receipt: None,
+            receipt_timestamp: None,
+            worker_version: Some(worker_version()),
+        }
+    }
+}
+
+/// Version reported as `worker_version`, to tell which build served a run
+pub fn worker_version() -> String {
+    match option_env!("CAPTCHA_ARK_COMMIT") {
+        Some(commit) => format!("{}+{}", env!("CARGO_PKG_VERSION"), commit),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// HTTP method used by the launchpad API
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// Request handed to an [`HttpClient`]
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+    pub connect_timeout: Duration,
+}
+
+impl HttpRequest {
+    fn new(method: Method, url: &str, connect_timeout: Duration) -> Self {
+        Self {
+            method,
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: None,
+            connect_timeout,
+        }
+    }
+
+    fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    fn json_body(self, body: &str) -> Self {
+        let mut request = self.header("Content-Type", "application/json");
+        request.body = Some(body.as_bytes().to_vec());
+        request
+    }
+}
+
+/// Response returned by an [`HttpClient`]
+pub trait HttpResponse {
+    fn status(&self) -> u16;
+    /// Header value by lowercase name
+    fn header(&self, name: &str) -> Option<String>;
+    /// Next chunk of at most `max_len` body bytes, or `None` at the end
+    fn chunk(&mut self, max_len: u64) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>;
+}
+
+/// Transport used for all launchpad requests
+pub trait HttpClient {
+    type Response: HttpResponse;
+
+    /// Send one request without following redirects
+    fn send(&self, request: &HttpRequest) -> Result<Self::Response, Box<dyn std::error::Error>>;
+}
+
+/// [`HttpClient`] backed by wasi-http, used by the worker binary
+pub struct WasiHttpClient;
+
+impl HttpClient for WasiHttpClient {
+    type Response = wasi_http_client::Response;
+
+    fn send(&self, request: &HttpRequest) -> Result<Self::Response, Box<dyn std::error::Error>> {
+        let client = wasi_http_client::Client::new();
+        let mut builder = match request.method {
+            Method::Get => client.get(&request.url),
+            Method::Post => client.post(&request.url),
+        }
+        .connect_timeout(request.connect_timeout);
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        if let Some(body) = &request.body {
+            builder = builder.body(body);
+        }
+        Ok(builder.send()?)
+    }
+}
+
+impl HttpResponse for wasi_http_client::Response {
+    fn status(&self) -> u16 {
+        wasi_http_client::Response::status(self)
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers().get(name).cloned()
+    }
+
+    fn chunk(&mut self, max_len: u64) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(wasi_http_client::Response::chunk(self, max_len)?)
+    }
+}
+
+/// Session ID that makes a dry run return a deterministic failure
+const DRY_RUN_FAIL_SESSION: &str = "dry-run-fail";
+
+/// Maximum number of HTTP redirects followed per request
+const MAX_REDIRECTS: usize = 3;
+
+/// How much the worker writes to stderr; each level includes the ones before
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    Error,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn parse(level: Option<&str>) -> Self {
+        match level {
+            Some("error") => Self::Error,
+            Some("debug") => Self::Debug,
+            _ => Self::Info,
+        }
+    }
+}
+
+/// Timeouts and counters for one worker run
+struct Run {
+    connect: Duration,
+    read: Duration,
+    started: Instant,
+    /// Hard deadline for the whole run; no read may go past it
+    deadline: Instant,
+    /// HTTP requests sent so far
+    attempts: Cell<u32>,
+    /// Launchpad URL that accepted the challenge
+    served_by: RefCell<Option<String>>,
+    /// Seed for retry jitter, derived from the session ID
+    jitter_seed: u64,
+    log_level: LogLevel,
+}
+
+impl Run {
+    fn from_input(input: &Input) -> Self {
+        let connect = input
+            .connect_timeout_secs
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS)
+            .clamp(1, MAX_CONNECT_TIMEOUT_SECS);
+        let read = input
+            .read_timeout_secs
+            .unwrap_or(DEFAULT_READ_TIMEOUT_SECS)
+            .clamp(1, MAX_READ_TIMEOUT_SECS);
+
+        let started = Instant::now();
+        Self {
+            connect: Duration::from_secs(connect),
+            read: Duration::from_secs(read),
+            started,
+            deadline: started + EXECUTION_BUDGET,
+            attempts: Cell::new(0),
+            served_by: RefCell::new(None),
+            jitter_seed: jitter_seed(&input.session_id),
+            log_level: LogLevel::parse(input.log_level.as_deref()),
+        }
+    }
+
+    /// Write a log line to stderr if `level` is enabled for this run
+    fn log(&self, level: LogLevel, message: impl std::fmt::Display) {
+        if level <= self.log_level {
+            eprintln!("{}", message);
+        }
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Delay before retry `attempt` (0-based): exponential backoff with full jitter
+    ///
+    /// The delay is uniform in `[0, min(max_ms, base_ms * 2^attempt)]`, drawn
+    /// from the session ID so a re-execution of the same run waits the same.
+    fn backoff_delay(&self, attempt: u32, base_ms: u64, max_ms: u64) -> Duration {
+        let ceiling = backoff_ceiling_ms(attempt, base_ms, max_ms);
+        let mut hasher = Sha256::new();
+        hasher.update(self.jitter_seed.to_be_bytes());
+        hasher.update(attempt.to_be_bytes());
+        let sample = u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap());
+        Duration::from_millis(sample % (ceiling + 1))
+    }
+}
+
+/// Upper bound of the backoff delay for retry `attempt`
+fn backoff_ceiling_ms(attempt: u32, base_ms: u64, max_ms: u64) -> u64 {
+    base_ms
+        .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX))
+        .min(max_ms)
+}
+
+/// Jitter seed for a session: first 8 bytes of sha256 of the session ID
+fn jitter_seed(session_id: &str) -> u64 {
+    u64::from_be_bytes(Sha256::digest(session_id.as_bytes())[..8].try_into().unwrap())
+}
+
+/// Clamp the re-poll interval and derive how many wait requests fit the budget
+///
+/// Returns `(interval, max_polls)`. The poll loop also checks the run
+/// deadline, so these only shape the polling, never extend it.
+fn poll_settings(input: &Input) -> (Duration, u32) {
+    let interval_ms = input
+        .poll_interval_ms
+        .unwrap_or(DEFAULT_POLL_INTERVAL_MS)
+        .clamp(MIN_POLL_INTERVAL_MS, MAX_POLL_INTERVAL_MS);
+
+    let budget_polls = (EXECUTION_BUDGET.as_millis() as u64 / interval_ms).max(1) as u32;
+    let max_polls = input.max_polls.map_or(budget_polls, |max| max.clamp(1, budget_polls));
+
+    (Duration::from_millis(interval_ms), max_polls)
+}
+
+/// Failure reason reported to the contract as `error_type` (and `status`)
+///
+/// Mirrors `CaptchaErrorType` in the token sale contract; the serialized
+/// names are the wire format, so a variant must be added to both.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaErrorType {
+    Timeout,
+    WrongAnswer,
+    RateLimited,
+    NetworkError,
+    AuthError,
+    SignatureInvalid,
+    Expired,
+    BindingMismatch,
+    RedirectError,
+    SystemError,
+    /// Any value this build doesn't know
+    #[serde(other)]
+    Unknown,
+}
+
+impl CaptchaErrorType {
+    /// Wire name, as used in `error_type` and `status`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaptchaErrorType::Timeout => "timeout",
+            CaptchaErrorType::WrongAnswer => "wrong_answer",
+            CaptchaErrorType::RateLimited => "rate_limited",
+            CaptchaErrorType::NetworkError => "network_error",
+            CaptchaErrorType::AuthError => "auth_error",
+            CaptchaErrorType::SignatureInvalid => "signature_invalid",
+            CaptchaErrorType::Expired => "expired",
+            CaptchaErrorType::BindingMismatch => "binding_mismatch",
+            CaptchaErrorType::RedirectError => "redirect_error",
+            CaptchaErrorType::SystemError => "system_error",
+            CaptchaErrorType::Unknown => "unknown",
+        }
+    }
+}
+
+/// Verification error carrying the `error_type` reported to the contract
+#[derive(Debug)]
+pub struct VerifyError {
+    pub error_type: CaptchaErrorType,
+    pub message: String,
+}
+
+impl VerifyError {
+    fn network(message: impl Into<String>) -> Self {
+        Self {
+            error_type: CaptchaErrorType::NetworkError,
+            message: message.into(),
+        }
+    }
+
+    fn auth(status: u16) -> Self {
+        Self {
+            error_type: CaptchaErrorType::AuthError,
+            message: format!("Launchpad rejected credentials (status {})", status),
+        }
+    }
+
+    fn rate_limited() -> Self {
+        Self {
+            error_type: CaptchaErrorType::RateLimited,
+            message: "Launchpad rate limit exceeded (status 429)".to_string(),
+        }
+    }
+
+    fn binding_mismatch(expected: &str, echoed: Option<&str>) -> Self {
+        Self {
+            error_type: CaptchaErrorType::BindingMismatch,
+            message: format!(
+                "Launchpad result is bound to {} tokens, purchase expects {}",
+                echoed.unwrap_or("no"),
+                expected
+            ),
+        }
+    }
+
+    fn expired() -> Self {
+        Self {
+            error_type: CaptchaErrorType::Expired,
+            message: "Challenge expired on the launchpad (status 404)".to_string(),
+        }
+    }
+
+    fn signature_invalid(message: impl Into<String>) -> Self {
+        Self {
+            error_type: CaptchaErrorType::SignatureInvalid,
+            message: message.into(),
+        }
+    }
+
+    fn redirect(message: impl Into<String>) -> Self {
+        Self {
+            error_type: CaptchaErrorType::RedirectError,
+            message: message.into(),
+        }
+    }
+
+    fn system(message: impl Into<String>) -> Self {
+        Self {
+            error_type: CaptchaErrorType::SystemError,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_input(message: impl Into<String>) -> Self {
+        Self {
+            error_type: CaptchaErrorType::SystemError,
+            message: format!("Invalid input: {}", message.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Overall worker deadline, kept under OutLayer's 40s `max_execution_seconds`
+const EXECUTION_BUDGET: Duration = Duration::from_secs(38);
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const MAX_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 10;
+const MAX_READ_TIMEOUT_SECS: u64 = 30;
+
+/// Retries of a rate-limited challenge creation on the same launchpad
+const CHALLENGE_RETRIES: u32 = 2;
+const RETRY_BASE_MS: u64 = 250;
+const RETRY_MAX_MS: u64 = 4_000;
+
+const DEFAULT_POLL_INTERVAL_MS: u64 = 2_000;
+const MIN_POLL_INTERVAL_MS: u64 = 250;
+const MAX_POLL_INTERVAL_MS: u64 = 10_000;
+
+/// Longest time the launchpad is asked to hold the long-poll open
+const MAX_WAIT_SECS: u64 = 60;
+
+/// Bytes of an unexpected response body quoted in error messages
+const BODY_PREVIEW_LEN: usize = 200;
+
+/// Bytes requested per body read
+const BODY_CHUNK_SIZE: u64 = 16 * 1024;
+
+/// Largest launchpad response body read, well under the worker's 128MB memory limit
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Wall-clock budget for the proof-of-work nonce search, leaving headroom
+/// for network calls within OutLayer's 40s `max_execution_seconds`
+const POW_TIME_BUDGET: Duration = Duration::from_secs(25);
+
+/// Maximum hashes attempted, keeping the search within `max_instructions`
+const POW_MAX_ATTEMPTS: u64 = 5_000_000;
+
+/// Launchpad API version this worker understands
+const SUPPORTED_API_VERSION: u32 = 1;
+
+/// Most challenges a multi-step CAPTCHA may chain in one run
+const MAX_CAPTCHA_STEPS: u32 = 5;
+
+/// Launchpad endpoint paths, overridable per input
+const DEFAULT_CHALLENGE_PATH: &str = "/api/captcha/challenge";
+const DEFAULT_WAIT_PATH_TEMPLATE: &str = "/api/captcha/wait/{challenge_id}";
+const DEFAULT_POW_PATH_TEMPLATE: &str = "/api/captcha/pow/{challenge_id}";
+const DEFAULT_VERIFY_PATH_TEMPLATE: &str = "/api/captcha/verify/{challenge_id}";
+const CHALLENGE_ID_PLACEHOLDER: &str = "{challenge_id}";
+
+/// Longest `challenge_id` accepted from the launchpad
+const MAX_CHALLENGE_ID_LEN: usize = 128;
+
+/// CAPTCHA kinds a launchpad can issue; the first is the default
+const CHALLENGE_TYPES: [&str; 2] = ["image", "audio"];
+
+#[derive(Deserialize)]
+struct ChallengeResponse {
+    challenge_id: String,
+    /// Launchpad API version (absent means v1)
+    #[serde(default)]
+    api_version: Option<u32>,
+    /// "pow" for proof-of-work challenges, absent for human CAPTCHAs
+    #[serde(default)]
+    provider: Option<String>,
+    /// Required leading zero bits of sha256(prefix || nonce) (PoW only)
+    #[serde(default)]
+    difficulty: Option<u32>,
+    /// Hash prefix issued by the launchpad (PoW only)
+    #[serde(default)]
+    prefix: Option<String>,
+    /// "solved" when the launchpad already has the answer (e.g. a trusted
+    /// session), absent when the user still has to solve
+    #[serde(default)]
+    status: Option<String>,
+    /// Verdict for a pre-solved challenge
+    #[serde(default)]
+    verified: Option<bool>,
+    /// `expected_tokens` echoed with a pre-solved verdict
+    #[serde(default)]
+    expected_tokens: Option<String>,
+}
+
+/// Immediate verdict for a submitted PoW nonce or answer
+#[derive(Deserialize)]
+struct SubmitResponse {
+    verified: bool,
+    /// `expected_tokens` the challenge was created with, echoed by the launchpad
+    #[serde(default)]
+    expected_tokens: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    status: String,  // "pending", "solved", "timeout"
+    verified: bool,
+    /// `expected_tokens` the challenge was created with, echoed by the launchpad
+    #[serde(default)]
+    expected_tokens: Option<String>,
+    /// Next challenge of a multi-step CAPTCHA, set on a solved step
+    #[serde(default)]
+    next_challenge_id: Option<String>,
+}
+
+/// Run the full verification flow for one purchase
+///
+/// Every verification outcome, including launchpad and network failures, is
+/// returned as an `Output` with its `error_type` and run metrics. `Err` means
+/// the input was rejected before any request was made.
+pub fn verify(input: &Input, client: &impl HttpClient) -> Result<Output, VerifyError> {
+    let amount = parse_amount(&input.amount)?;
+    validate_account_id("buyer", &input.buyer)?;
+    validate_endpoint_paths(input)?;
+    let challenge_type = challenge_type(input)?;
+    if let Some(expected_tokens) = &input.expected_tokens {
+        expected_tokens.parse::<u128>().map_err(|_| {
+            VerifyError::invalid_input(format!("expected_tokens {:?} is not a decimal number", expected_tokens))
+        })?;
+    }
+
+    // Read transaction hash from environment (if available)
+    let transaction_hash = std::env::var("NEAR_TRANSACTION_HASH")
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    let run = Run::from_input(input);
+    run.log(LogLevel::Info, format_args!("🔍 Transaction hash: {}", transaction_hash));
+
+    // Execute CAPTCHA verification flow
+    let (verified, error, error_type) =
+        match verify_captcha(input, &transaction_hash, client, &run) {
+            Ok((verified, error_type)) => (verified, None, error_type),
+            Err(e) => {
+                let error_type = e
+                    .downcast_ref::<VerifyError>()
+                    .map_or(CaptchaErrorType::SystemError, |e| e.error_type);
+                (false, Some(format!("Verification failed: {}", e)), Some(error_type))
+            }
+        };
+
+    let status = match (verified, &error_type) {
+        (true, _) => "verified".to_string(),
+        (false, Some(error_type)) => error_type.as_str().to_string(),
+        (false, None) => CaptchaErrorType::SystemError.as_str().to_string(),
+    };
+
+    let (receipt, receipt_timestamp) = match receipt_secret() {
+        Some(secret) => {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs());
+            let receipt = verification_receipt(&secret, input, amount, verified, timestamp);
+            (Some(receipt), Some(timestamp))
+        }
+        None => (None, None),
+    };
+
+    // One summary line per run, whatever the log level
+    let solve_latency_ms = run.elapsed_ms();
+    let served_by = run.served_by.take();
+    eprintln!(
+        "📈 Run finished: status={}, attempts={}, latency={}ms, served_by={}",
+        status,
+        run.attempts.get(),
+        solve_latency_ms,
+        served_by.as_deref().unwrap_or("none")
+    );
+
+    Ok(Output {
+        verified,
+        status,
+        session_id: input.session_id.clone(),
+        error,
+        error_type,
+        solve_latency_ms: Some(solve_latency_ms),
+        attempts: Some(run.attempts.get()),
+        served_by,
+        challenge_type: Some(challenge_type.to_string()),
+        receipt,
+        receipt_timestamp,
+        worker_version: Some(worker_version()),
+    })
+}
+
+/// Parse the raw worker input and run [`verify`]
+///
+/// Empty or malformed input yields a `system_error` output with an empty
+/// `session_id` instead of an error, so the contract always gets valid JSON.
+pub fn verify_json(raw_input: &str, client: &impl HttpClient) -> Output {
+    match serde_json::from_str::<Input>(raw_input) {
+        Ok(input) => verify(&input, client).unwrap_or_else(|e| Output::failure(&input.session_id, &e)),
+        Err(e) => {
+            // No input to read a log level from; errors always log anyway
+            eprintln!("❌ Invalid worker input: {}", e);
+            Output::failure("", &VerifyError::invalid_input(format!("malformed JSON ({})", e)))
+        }
+    }
+}
+
+/// Read the optional launchpad API token.
+///
+/// OutLayer decrypts the contract's `secrets_ref` into environment variables,
+/// so the token never appears in `input_data`. Never log its value.
+fn api_token() -> Option<String> {
+    std::env::var("API_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+/// Read the optional receipt signing secret, injected from `secrets_ref`
+/// like `API_TOKEN`. Never log its value.
+fn receipt_secret() -> Option<String> {
+    std::env::var("RECEIPT_SECRET").ok().filter(|secret| !secret.is_empty())
+}
+
+/// Read the optional secret the launchpad signs verify responses with,
+/// injected from `secrets_ref` like `API_TOKEN`. Never log its value.
+fn response_secret() -> Option<String> {
+    std::env::var("RESPONSE_SECRET").ok().filter(|secret| !secret.is_empty())
+}
+
+/// Check the launchpad's `X-Signature` (hex HMAC-SHA256 of the raw body)
+///
+/// Without a secret every response is accepted, so unsigned backends keep
+/// working; with one, a missing or wrong signature is `signature_invalid`.
+fn check_signature(secret: Option<&str>, signature: Option<&str>, body: &[u8]) -> Result<(), VerifyError> {
+    let Some(secret) = secret else {
+        return Ok(());
+    };
+    let signature = signature
+        .ok_or_else(|| VerifyError::signature_invalid("Verify response is not signed"))?;
+    let signature = from_hex(signature.trim())
+        .ok_or_else(|| VerifyError::signature_invalid("Verify response signature is not hex"))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    if constant_time_eq(&mac.finalize().into_bytes(), &signature) {
+        Ok(())
+    } else {
+        Err(VerifyError::signature_invalid("Verify response signature mismatch"))
+    }
+}
+
+/// Compare secret-derived bytes without exiting early on the first difference
+///
+/// Use this for every comparison involving a secret, so timing doesn't reveal
+/// how much of a guess was right. Only the lengths, which aren't secret, are
+/// compared directly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// HMAC-SHA256 receipt binding the verification result to the purchase
+///
+/// Fields are joined with `|`, so the launchpad can recompute it from the
+/// values it saw and the timestamp in the output.
+fn verification_receipt(secret: &str, input: &Input, amount: u128, verified: bool, timestamp: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(
+        format!("{}|{}|{}|{}|{}", input.session_id, input.buyer, amount, verified, timestamp).as_bytes(),
+    );
+    to_hex(&mac.finalize().into_bytes())
+}
+
+/// Canned result for dry runs: fails with "wrong_answer" for the magic
+/// `DRY_RUN_FAIL_SESSION`, succeeds for any other session
+fn dry_run_result(input: &Input, run: &Run) -> (bool, Option<CaptchaErrorType>) {
+    if input.session_id == DRY_RUN_FAIL_SESSION {
+        run.log(LogLevel::Info, "🧪 Dry run: simulating failed verification");
+        (false, Some(CaptchaErrorType::WrongAnswer))
+    } else {
+        run.log(LogLevel::Info, "🧪 Dry run: simulating successful verification");
+        (true, None)
+    }
+}
+
+/// Attach the bearer token, if any, to a request
+fn with_auth(request: HttpRequest, api_token: Option<&str>) -> HttpRequest {
+    match api_token {
+        Some(token) => request.header("Authorization", format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+/// Read a response body in chunks, failing with a network error once the
+/// read timeout or the run deadline passes.
+///
+/// wasi-http has no read timeout of its own, so this is checked between
+/// chunks; a single stalled chunk is still bounded by OutLayer's hard limit.
+fn read_body(mut response: impl HttpResponse, run: &Run) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let read_deadline = (Instant::now() + run.read).min(run.deadline);
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response.chunk(BODY_CHUNK_SIZE)? {
+        if body.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(VerifyError::system(format!(
+                "Response body exceeds {} bytes, refusing to read it",
+                MAX_BODY_SIZE
+            ))
+            .into());
+        }
+        body.extend_from_slice(&chunk);
+        if Instant::now() > read_deadline {
+            return Err(VerifyError::network(format!(
+                "Read timeout after {}s ({} bytes received)",
+                run.read.as_secs(),
+                body.len()
+            ))
+            .into());
+        }
+    }
+
+    Ok(body)
+}
+
+/// Parse a JSON response body, rejecting empty bodies and non-JSON content
+/// types (e.g. an HTML error page from a proxy) with a preview of the body
+fn parse_json_body<T: DeserializeOwned>(
+    what: &str,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<T, Box<dyn std::error::Error>> {
+    if body.is_empty() {
+        return Err(format!("Empty {} response from launchpad", what).into());
+    }
+
+    if let Some(content_type) = content_type {
+        if !content_type.to_ascii_lowercase().contains("json") {
+            return Err(format!(
+                "Unexpected {} response content type '{}': {}",
+                what,
+                content_type,
+                body_preview(body)
+            )
+            .into());
+        }
+    }
+
+    serde_json::from_slice(body).map_err(|e| {
+        format!("Invalid {} response JSON ({}): {}", what, e, body_preview(body)).into()
+    })
+}
+
+/// First `BODY_PREVIEW_LEN` bytes of a body, for diagnostics
+fn body_preview(body: &[u8]) -> String {
+    let preview = String::from_utf8_lossy(&body[..body.len().min(BODY_PREVIEW_LEN)]);
+    if body.len() > BODY_PREVIEW_LEN {
+        format!("{}...", preview)
+    } else {
+        preview.into_owned()
+    }
+}
+
+/// Send a request, following up to `MAX_REDIRECTS` same-origin redirects
+///
+/// The same request is re-sent to the new URL on every hop. Cross-origin and
+/// https -> http redirects are refused so the bearer token never leaves the
+/// launchpad host.
+fn send_following_redirects<C: HttpClient>(
+    client: &C,
+    mut request: HttpRequest,
+    run: &Run,
+) -> Result<C::Response, Box<dyn std::error::Error>> {
+    let url = request.url.clone();
+
+    for _ in 0..=MAX_REDIRECTS {
+        run.attempts.set(run.attempts.get() + 1);
+        let response = client.send(&request).map_err(|e| {
+            VerifyError::network(format!("Request to {} failed: {}", request.url, e))
+        })?;
+        let status = response.status();
+        if !(300..400).contains(&status) {
+            return Ok(response);
+        }
+
+        let location = response.header("location").ok_or_else(|| {
+            VerifyError::redirect(format!("Redirect (status {}) without Location header", status))
+        })?;
+        let next = resolve_redirect(&url, &location).map_err(VerifyError::redirect)?;
+
+        run.log(LogLevel::Info, format_args!("↪️  Following redirect (status {}) to {}", status, next));
+        request.url = next;
+    }
+
+    Err(VerifyError::redirect(format!("Too many redirects (max {})", MAX_REDIRECTS)).into())
+}
+
+/// Resolve a `Location` header against the original URL, refusing targets
+/// on a different host or downgraded from https
+fn resolve_redirect(original: &str, location: &str) -> Result<String, String> {
+    let (scheme, host) =
+        split_origin(original).ok_or_else(|| format!("Malformed URL: {}", original))?;
+
+    let target = if location.starts_with('/') && !location.starts_with("//") {
+        format!("{}://{}{}", scheme, host, location)
+    } else {
+        location.to_string()
+    };
+
+    let (target_scheme, target_host) =
+        split_origin(&target).ok_or_else(|| format!("Malformed redirect target: {}", location))?;
+
+    if target_host != host {
+        return Err(format!("Refusing cross-origin redirect to {}", target_host));
+    }
+    if target_scheme != "https" && (scheme == "https" || target_scheme != "http") {
+        return Err(format!("Refusing redirect to insecure URL: {}", target));
+    }
+
+    Ok(target)
+}
+
+/// Split an absolute http(s) URL into `(scheme, host[:port])`
+fn split_origin(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+    let host = rest.split(['/', '?', '#']).next()?;
+    if host.is_empty() {
+        return None;
+    }
+    Some((scheme, host))
+}
+
+/// Create a challenge on the first launchpad that accepts it
+///
+/// Returns the launchpad URL that served the challenge, so the rest of the
+/// flow talks to the same host. Only network errors (unreachable host, 5xx)
+/// move on to the next URL; any other error is returned as is. With a single
+/// URL its error is returned unchanged; with several, a network error is
+/// returned once all have failed.
+fn create_challenge_with_failover<'a>(
+    client: &impl HttpClient,
+    launchpad_urls: &[&'a str],
+    challenge_path: &str,
+    challenge_body: &str,
+    idempotency_key: &str,
+    api_token: Option<&str>,
+    run: &Run,
+) -> Result<(&'a str, ChallengeResponse), Box<dyn std::error::Error>> {
+    if launchpad_urls.is_empty() {
+        return Err("No launchpad URL provided".into());
+    }
+    if let Some(invalid) = launchpad_urls.iter().find(|url| split_origin(url).is_none()) {
+        return Err(format!("Invalid launchpad URL (expected http(s)://host): {}", invalid).into());
+    }
+
+    let mut failures = Vec::new();
+    for &launchpad_url in launchpad_urls {
+        match create_challenge_with_retry(
+            client,
+            launchpad_url,
+            challenge_path,
+            challenge_body,
+            idempotency_key,
+            api_token,
+            run,
+        ) {
+            Ok(challenge) => return Ok((launchpad_url, challenge)),
+            Err(e) if launchpad_urls.len() == 1 || !is_network_error(e.as_ref()) => return Err(e),
+            Err(e) => {
+                run.log(LogLevel::Error, format_args!("⚠️  Launchpad {} failed: {}", launchpad_url, e));
+                failures.push(format!("{}: {}", launchpad_url, e));
+            }
+        }
+    }
+
+    Err(VerifyError::network(format!("All launchpads failed ({})", failures.join("; "))).into())
+}
+
+/// Create a challenge on one launchpad, backing off and retrying while it
+/// answers 429 and the run budget allows
+fn create_challenge_with_retry(
+    client: &impl HttpClient,
+    launchpad_url: &str,
+    challenge_path: &str,
+    challenge_body: &str,
+    idempotency_key: &str,
+    api_token: Option<&str>,
+    run: &Run,
+) -> Result<ChallengeResponse, Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    loop {
+        match create_challenge(client, launchpad_url, challenge_path, challenge_body, idempotency_key, api_token, run) {
+            Err(e) if attempt < CHALLENGE_RETRIES && is_rate_limited(e.as_ref()) => {
+                let delay = run.backoff_delay(attempt, RETRY_BASE_MS, RETRY_MAX_MS);
+                if run.remaining() <= delay + run.read {
+                    return Err(e);
+                }
+                run.log(LogLevel::Info, format_args!("🚦 Rate limited by {}, retrying in {}ms...", launchpad_url, delay.as_millis()));
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_rate_limited(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<VerifyError>()
+        .is_some_and(|e| e.error_type == CaptchaErrorType::RateLimited)
+}
+
+/// Whether an error means the launchpad couldn't be reached or is failing,
+/// as opposed to a definite answer from it
+fn is_network_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<VerifyError>()
+        .is_some_and(|e| e.error_type == CaptchaErrorType::NetworkError)
+}
+
+/// Create a CAPTCHA challenge on one launchpad
+///
+/// The launchpad must return the existing challenge, not create a new one,
+/// when it sees an `Idempotency-Key` it has already served.
+fn create_challenge(
+    client: &impl HttpClient,
+    launchpad_url: &str,
+    challenge_path: &str,
+    challenge_body: &str,
+    idempotency_key: &str,
+    api_token: Option<&str>,
+    run: &Run,
+) -> Result<ChallengeResponse, Box<dyn std::error::Error>> {
+    let challenge_url = format!("{}{}", launchpad_url, challenge_path);
+
+    run.log(LogLevel::Info, format_args!("📤 Creating CAPTCHA challenge on {}...", launchpad_url));
+    let request = HttpRequest::new(Method::Post, &challenge_url, run.connect)
+        .header("Idempotency-Key", idempotency_key)
+        .json_body(challenge_body);
+    let challenge_response = send_following_redirects(client, with_auth(request, api_token), run)?;
+
+    // Check response status
+    let status = challenge_response.status();
+    if status == 401 || status == 403 {
+        return Err(VerifyError::auth(status).into());
+    }
+    if status == 429 {
+        return Err(VerifyError::rate_limited().into());
+    }
+    if status >= 500 {
+        return Err(VerifyError::network(format!("Launchpad unavailable. Status: {}", status)).into());
+    }
+    if !(200..300).contains(&status) {
+        match read_body(challenge_response, run) {
+            Ok(body_bytes) => {
+                let error_text = String::from_utf8_lossy(&body_bytes);
+                return Err(format!("Failed to create challenge. Status: {}. Details: {}", status, error_text).into());
+            }
+            Err(e) => {
+                return Err(format!("Failed to create challenge. Status: {}. Failed to read body: {:?}", status, e).into());
+            }
+        }
+    }
+
+    // Parse response
+    let content_type = challenge_response.header("content-type");
+    let response_body = read_body(challenge_response, run)?;
+    let challenge: ChallengeResponse =
+        parse_json_body("challenge", content_type.as_deref(), &response_body)?;
+
+    let api_version = challenge.api_version.unwrap_or(1);
+    if api_version != SUPPORTED_API_VERSION {
+        return Err(format!(
+            "Unsupported launchpad API version {} (worker supports v{})",
+            api_version, SUPPORTED_API_VERSION
+        )
+        .into());
+    }
+
+    Ok(challenge)
+}
+
+/// Long-poll the launchpad once for the challenge result
+///
+/// The backend holds the connection open until the user solves or the hold
+/// expires. The hold is shortened so reading the reply still fits the deadline
+/// and sent as a `timeout` query parameter on `wait_url`.
+fn wait_for_solution(
+    client: &impl HttpClient,
+    wait_url: &str,
+    api_token: Option<&str>,
+    response_secret: Option<&str>,
+    run: &Run,
+) -> Result<VerifyResponse, Box<dyn std::error::Error>> {
+    let wait_secs = run
+        .remaining()
+        .saturating_sub(run.read)
+        .as_secs()
+        .min(MAX_WAIT_SECS);
+    let separator = if wait_url.contains('?') { '&' } else { '?' };
+    let wait_url = format!("{}{}timeout={}", wait_url, separator, wait_secs);
+
+    run.log(LogLevel::Info, format_args!("⏳ Waiting for user to solve CAPTCHA ({}s timeout)...", wait_secs));
+
+    // Only connecting is bounded here; the hold itself is bounded by read_body
+    let request = HttpRequest::new(Method::Get, &wait_url, run.connect);
+    let verify_response = send_following_redirects(client, with_auth(request, api_token), run)?;
+
+    // Check response status
+    let status = verify_response.status();
+    if status == 401 || status == 403 {
+        return Err(VerifyError::auth(status).into());
+    }
+    if status == 404 {
+        // The launchpad no longer knows the challenge, usually because it expired
+        return Err(VerifyError::expired().into());
+    }
+    if status == 429 {
+        return Err(VerifyError::rate_limited().into());
+    }
+    if !(200..300).contains(&status) {
+        match read_body(verify_response, run) {
+            Ok(body_bytes) => {
+                let error_text = String::from_utf8_lossy(&body_bytes);
+                return Err(format!("Failed to verify CAPTCHA. Status: {}. Details: {}", status, error_text).into());
+            }
+            Err(e) => {
+                return Err(format!("Failed to verify CAPTCHA. Status: {}. Failed to read body: {:?}", status, e).into());
+            }
+        }
+    }
+
+    // Parse response
+    let content_type = verify_response.header("content-type");
+    let signature = verify_response.header("x-signature");
+    let verify_body = read_body(verify_response, run)?;
+
+    run.log(LogLevel::Debug, format_args!("📥 Raw verify response: {}", String::from_utf8_lossy(&verify_body)));
+
+    // Don't trust `verified` from a response that may have been forged in transit
+    check_signature(response_secret, signature.as_deref(), &verify_body)?;
+
+    let verify_data: VerifyResponse =
+        parse_json_body("verify", content_type.as_deref(), &verify_body)?;
+
+    run.log(
+        LogLevel::Debug,
+        format_args!("📊 Parsed verify response: status={}, verified={}", verify_data.status, verify_data.verified),
+    );
+
+    Ok(verify_data)
+}
+
+fn verify_captcha(
+    input: &Input,
+    transaction_hash: &str,
+    client: &impl HttpClient,
+    run: &Run,
+) -> Result<(bool, Option<CaptchaErrorType>), Box<dyn std::error::Error>> {
+    if input.dry_run.unwrap_or(false) {
+        return Ok(dry_run_result(input, run));
+    }
+
+    let api_token = api_token();
+    if api_token.is_some() {
+        run.log(LogLevel::Info, "🔑 Using API token from secrets");
+    }
+    let response_secret = response_secret();
+
+    // Step 1: Request CAPTCHA challenge from launchpad
+    let mut challenge_body = serde_json::json!({
+        "session_id": input.session_id,
+        "buyer": input.buyer,
+        "amount": input.amount,
+        "transaction_hash": transaction_hash,
+        "challenge_type": challenge_type(input)?,
+        "nonce": challenge_nonce(input)
+    });
+    if let Some(difficulty) = input.difficulty {
+        challenge_body["difficulty"] = difficulty.into();
+    }
+    if let Some(sale_id) = &input.sale_id {
+        challenge_body["sale_id"] = sale_id.as_str().into();
+    }
+    if let Some(client_ip) = &input.client_ip {
+        challenge_body["client_ip"] = client_ip.as_str().into();
+    }
+    if let Some(user_agent) = &input.user_agent {
+        challenge_body["user_agent"] = user_agent.as_str().into();
+    }
+    if let Some(expected_tokens) = &input.expected_tokens {
+        challenge_body["expected_tokens"] = expected_tokens.as_str().into();
+    }
+    let challenge_body = serde_json::to_string(&challenge_body)?;
+
+    // One key for the whole run, so retries and failover dedupe server-side
+    let idempotency_key = input
+        .idempotency_key
+        .clone()
+        .unwrap_or_else(|| default_idempotency_key(&input.session_id));
+
+    let launchpad_urls: Vec<&str> = input
+        .launchpad_url
+        .as_slice()
+        .iter()
+        .chain(&input.fallback_launchpad_urls)
+        .map(String::as_str)
+        .collect();
+    let (launchpad_url, challenge_data) = create_challenge_with_failover(
+        client,
+        &launchpad_urls,
+        input.challenge_path.as_deref().unwrap_or(DEFAULT_CHALLENGE_PATH),
+        &challenge_body,
+        &idempotency_key,
+        api_token.as_deref(),
+        run,
+    )?;
+    run.served_by.replace(Some(launchpad_url.to_string()));
+    check_challenge_id(&challenge_data.challenge_id)?;
+
+    // The launchpad may already know the answer, saving the long-poll
+    if let Some(result) = presolved_result(&challenge_data, response_secret.as_deref()) {
+        run.log(LogLevel::Info, "⚡ Launchpad returned the challenge already solved");
+        check_binding(input, challenge_data.expected_tokens.as_deref())?;
+        return Ok(result);
+    }
+
+    // Backends without a human CAPTCHA issue a proof-of-work challenge instead
+    if challenge_data.provider.as_deref() == Some("pow") {
+        return solve_pow_challenge(
+            client,
+            launchpad_url,
+            &challenge_data,
+            input,
+            api_token.as_deref(),
+            response_secret.as_deref(),
+            run,
+        );
+    }
+
+    // Frontend already collected the answer: verify it synchronously
+    if let Some(answer) = &input.answer {
+        return submit_answer(
+            client,
+            launchpad_url,
+            &challenge_data.challenge_id,
+            answer,
+            input,
+            api_token.as_deref(),
+            response_secret.as_deref(),
+            run,
+        );
+    }
+
+    // Step 2: Long-polling for user's CAPTCHA solution. Multi-step CAPTCHAs
+    // chain further challenges through `next_challenge_id`; each is waited on
+    // in turn within the same run deadline.
+    let mut challenge_id = challenge_data.challenge_id;
+    let mut step = 1;
+    loop {
+        let verify_data = poll_for_solution(
+            client,
+            &wait_url(input, launchpad_url, &challenge_id),
+            input,
+            api_token.as_deref(),
+            response_secret.as_deref(),
+            run,
+        )?;
+
+        match verify_data.status.as_str() {
+            "solved" => {
+                check_binding(input, verify_data.expected_tokens.as_deref())?;
+                if !verify_data.verified {
+                    run.log(LogLevel::Info, "❌ CAPTCHA verification failed (wrong answer)");
+                    return Ok((false, Some(CaptchaErrorType::WrongAnswer)));
+                }
+                let Some(next_challenge_id) = verify_data.next_challenge_id else {
+                    run.log(LogLevel::Info, "✅ CAPTCHA verified successfully!");
+                    return Ok((true, None));
+                };
+                check_challenge_id(&next_challenge_id)?;
+                if step >= MAX_CAPTCHA_STEPS {
+                    run.log(
+                        LogLevel::Error,
+                        format_args!("❌ Launchpad issued more than {} CAPTCHA steps", MAX_CAPTCHA_STEPS),
+                    );
+                    return Ok((false, Some(CaptchaErrorType::SystemError)));
+                }
+                if run.remaining() <= run.read {
+                    run.log(LogLevel::Info, "⏱️  No time left for the next CAPTCHA step");
+                    return Ok((false, Some(CaptchaErrorType::Timeout)));
+                }
+                step += 1;
+                run.log(
+                    LogLevel::Info,
+                    format_args!("➡️  Step solved, waiting on step {} (challenge {})", step, next_challenge_id),
+                );
+                challenge_id = next_challenge_id;
+            }
+            "timeout" => {
+                run.log(LogLevel::Info, "⏱️  CAPTCHA timeout - user didn't solve in time");
+                return Ok((false, Some(CaptchaErrorType::Timeout)));
+            }
+            "pending" => {
+                // Polling budget exhausted but challenge still pending
+                run.log(LogLevel::Info, "⏳ Long-poll timeout, treating as timeout");
+                return Ok((false, Some(CaptchaErrorType::Timeout)));
+            }
+            _ => {
+                run.log(LogLevel::Error, format_args!("❌ Unknown status: {}", verify_data.status));
+                return Ok((false, Some(CaptchaErrorType::SystemError)));
+            }
+        }
+    }
+}
+
+/// Long-poll one challenge, re-polling while the launchpad still reports
+/// it pending, until `max_polls` or the run deadline is reached
+fn poll_for_solution(
+    client: &impl HttpClient,
+    wait_url: &str,
+    input: &Input,
+    api_token: Option<&str>,
+    response_secret: Option<&str>,
+    run: &Run,
+) -> Result<VerifyResponse, Box<dyn std::error::Error>> {
+    let (poll_interval, max_polls) = poll_settings(input);
+    let mut verify_data = wait_for_solution(client, wait_url, api_token, response_secret, run)?;
+    let mut polls = 1;
+    while verify_data.status == "pending"
+        && polls < max_polls
+        && run.remaining() > poll_interval + run.read
+    {
+        run.log(
+            LogLevel::Info,
+            format_args!(
+                "⏳ Still pending, polling again in {}ms ({}/{})...",
+                poll_interval.as_millis(),
+                polls + 1,
+                max_polls
+            ),
+        );
+        std::thread::sleep(poll_interval);
+        verify_data = wait_for_solution(client, wait_url, api_token, response_secret, run)?;
+        polls += 1;
+    }
+    Ok(verify_data)
+}
+
+/// Wait endpoint URL for a challenge, without the `timeout` parameter
+fn wait_url(input: &Input, launchpad_url: &str, challenge_id: &str) -> String {
+    let template = input
+        .wait_path_template
+        .as_deref()
+        .unwrap_or(DEFAULT_WAIT_PATH_TEMPLATE);
+    let url = challenge_url(launchpad_url, template, challenge_id);
+    match &input.expected_tokens {
+        Some(expected_tokens) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{}{}expected_tokens={}", url, separator, expected_tokens)
+        }
+        None => url,
+    }
+}
+
+/// URL of a per-challenge endpoint: `template` with `{challenge_id}` filled in
+fn challenge_url(launchpad_url: &str, template: &str, challenge_id: &str) -> String {
+    format!("{}{}", launchpad_url, template.replace(CHALLENGE_ID_PLACEHOLDER, &encode_path_segment(challenge_id)))
+}
+
+/// Reject a challenge id that can't be a real one: empty, or longer than
+/// `MAX_CHALLENGE_ID_LEN`
+fn check_challenge_id(challenge_id: &str) -> Result<(), VerifyError> {
+    if challenge_id.is_empty() {
+        return Err(VerifyError::system("Launchpad returned an empty challenge_id"));
+    }
+    if challenge_id.len() > MAX_CHALLENGE_ID_LEN {
+        return Err(VerifyError::system(format!(
+            "Launchpad returned a {}-byte challenge_id, maximum is {}",
+            challenge_id.len(),
+            MAX_CHALLENGE_ID_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters, so a
+/// challenge id can't add path segments or break the URL
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Check that a solved result is bound to this purchase's `expected_tokens`
+///
+/// Stops a solution to a cheaper purchase's challenge from being replayed.
+/// Nothing is checked when the contract sent no `expected_tokens`.
+fn check_binding(input: &Input, echoed: Option<&str>) -> Result<(), VerifyError> {
+    match &input.expected_tokens {
+        Some(expected) if echoed != Some(expected.as_str()) => Err(VerifyError::binding_mismatch(expected, echoed)),
+        _ => Ok(()),
+    }
+}
+
+/// Check custom endpoint paths: all must start with `/`, and the wait, PoW
+/// and verify templates must contain `{challenge_id}`
+fn validate_endpoint_paths(input: &Input) -> Result<(), VerifyError> {
+    for (field, path) in [
+        ("challenge_path", &input.challenge_path),
+        ("wait_path_template", &input.wait_path_template),
+        ("pow_path_template", &input.pow_path_template),
+        ("verify_path_template", &input.verify_path_template),
+    ] {
+        if let Some(path) = path {
+            if !path.starts_with('/') {
+                return Err(VerifyError::invalid_input(format!("{} {:?} must start with '/'", field, path)));
+            }
+        }
+    }
+    for (field, template) in [
+        ("wait_path_template", &input.wait_path_template),
+        ("pow_path_template", &input.pow_path_template),
+        ("verify_path_template", &input.verify_path_template),
+    ] {
+        if let Some(template) = template {
+            if !template.contains(CHALLENGE_ID_PLACEHOLDER) {
+                return Err(VerifyError::invalid_input(format!(
+                    "{} {:?} must contain {}",
+                    field, template, CHALLENGE_ID_PLACEHOLDER
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Requested CAPTCHA kind, defaulting to an image challenge
+fn challenge_type(input: &Input) -> Result<&'static str, VerifyError> {
+    match input.challenge_type.as_deref() {
+        None => Ok(CHALLENGE_TYPES[0]),
+        Some(kind) => CHALLENGE_TYPES.iter().copied().find(|known| *known == kind).ok_or_else(|| {
+            VerifyError::invalid_input(format!("challenge_type {:?} must be one of {:?}", kind, CHALLENGE_TYPES))
+        }),
+    }
+}
+
+/// Verdict from a challenge the launchpad returned as already solved
+///
+/// `None` keeps the normal flow. Challenge responses aren't signed, so with a
+/// `RESPONSE_SECRET` configured a pre-solved verdict is ignored and the
+/// signed long-poll decides instead.
+fn presolved_result(challenge: &ChallengeResponse, response_secret: Option<&str>) -> Option<(bool, Option<CaptchaErrorType>)> {
+    if challenge.status.as_deref() != Some("solved") || response_secret.is_some() {
+        return None;
+    }
+    if challenge.verified == Some(true) {
+        Some((true, None))
+    } else {
+        Some((false, Some(CaptchaErrorType::WrongAnswer)))
+    }
+}
+
+/// Solve a proof-of-work challenge and submit the nonce to the launchpad
+///
+/// The nonce is POSTed as `{"nonce": "<decimal>"}` to `pow_path_template`
+/// (default `/api/captcha/pow/{challenge_id}`), which replies with `{"verified": bool}`
+/// and the challenge's `expected_tokens`, bound like a wait result.
+fn solve_pow_challenge(
+    client: &impl HttpClient,
+    launchpad_url: &str,
+    challenge: &ChallengeResponse,
+    input: &Input,
+    api_token: Option<&str>,
+    response_secret: Option<&str>,
+    run: &Run,
+) -> Result<(bool, Option<CaptchaErrorType>), Box<dyn std::error::Error>> {
+    let prefix = challenge.prefix.as_deref().ok_or("PoW challenge is missing prefix")?;
+    let difficulty = challenge.difficulty.ok_or("PoW challenge is missing difficulty")?;
+    if difficulty > 256 {
+        return Err(format!("Invalid PoW difficulty: {}", difficulty).into());
+    }
+
+    run.log(LogLevel::Info, format_args!("⛏️  Solving proof-of-work challenge (difficulty: {} bits)...", difficulty));
+
+    let deadline = (Instant::now() + POW_TIME_BUDGET).min(run.deadline);
+    let nonce = match find_pow_nonce(prefix, difficulty, deadline, POW_MAX_ATTEMPTS) {
+        Some(nonce) => nonce,
+        None => {
+            run.log(LogLevel::Info, "⏱️  Proof-of-work search exceeded its budget");
+            return Ok((false, Some(CaptchaErrorType::Timeout)));
+        }
+    };
+
+    run.log(LogLevel::Info, format_args!("📤 Submitting proof-of-work nonce {}...", nonce));
+    let template = input.pow_path_template.as_deref().unwrap_or(DEFAULT_POW_PATH_TEMPLATE);
+    let submit_url = challenge_url(launchpad_url, template, &challenge.challenge_id);
+    let submit_body = serde_json::json!({ "nonce": nonce.to_string() }).to_string();
+    let submit_data: SubmitResponse =
+        post_json(client, &submit_url, &submit_body, "PoW submit", api_token, response_secret, run)?;
+    check_binding(input, submit_data.expected_tokens.as_deref())?;
+    if submit_data.verified {
+        run.log(LogLevel::Info, "✅ Proof-of-work accepted!");
+        Ok((true, None))
+    } else {
+        run.log(LogLevel::Info, "❌ Proof-of-work rejected by launchpad");
+        Ok((false, Some(CaptchaErrorType::WrongAnswer)))
+    }
+}
+
+/// Idempotency key for a session: hex sha256 of the session ID
+fn default_idempotency_key(session_id: &str) -> String {
+    to_hex(&Sha256::digest(format!("captcha-ark:{}", session_id).as_bytes()))
+}
+
+/// Nonce for the challenge request: hex sha256 of the purchase it is for
+///
+/// Derived rather than random so every OutLayer re-execution of the same run
+/// sends the same nonce.
+fn challenge_nonce(input: &Input) -> String {
+    let preimage = format!("captcha-ark-nonce:{}|{}|{}", input.session_id, input.buyer, input.amount);
+    to_hex(&Sha256::digest(preimage.as_bytes()))
+}
+
+/// Lowercase hex encoding
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode lowercase or uppercase hex, `None` if malformed
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((char::from(*high).to_digit(16)? * 16 + char::from(*low).to_digit(16)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse the purchase amount (yoctoNEAR as a decimal string)
+///
+/// Only used to reject garbage early; the launchpad is sent the original
+/// string, exactly as the contract wrote it.
+fn parse_amount(amount: &str) -> Result<u128, VerifyError> {
+    use std::num::IntErrorKind;
+
+    amount.parse::<u128>().map_err(|e| {
+        VerifyError::invalid_input(match e.kind() {
+            IntErrorKind::Empty => "amount is empty".to_string(),
+            IntErrorKind::PosOverflow => format!("amount {} overflows u128", amount),
+            _ => format!("amount {:?} is not a decimal number", amount),
+        })
+    })
+}
+
+/// Check an account ID against NEAR's rules before sending it to the launchpad
+///
+/// 2-64 characters of `a-z`, `0-9` and the separators `.`, `-`, `_`, starting
+/// and ending with a letter or digit, with no two separators in a row.
+/// Implicit (64 hex) accounts follow the same rules.
+fn validate_account_id(field: &str, account_id: &str) -> Result<(), VerifyError> {
+    let invalid = |reason: &str| {
+        Err(VerifyError::invalid_input(format!("{} {:?} {}", field, account_id, reason)))
+    };
+
+    if !(2..=64).contains(&account_id.len()) {
+        return invalid("must be 2-64 characters long");
+    }
+    let is_separator = |c: char| matches!(c, '.' | '-' | '_');
+    let mut previous_separator = true;
+    for c in account_id.chars() {
+        if is_separator(c) {
+            if previous_separator {
+                return invalid("has a misplaced separator");
+            }
+            previous_separator = true;
+        } else if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            previous_separator = false;
+        } else {
+            return invalid("may only contain a-z, 0-9, '.', '-' and '_'");
+        }
+    }
+    if previous_separator {
+        return invalid("has a misplaced separator");
+    }
+
+    Ok(())
+}
+
+/// Submit a pre-collected answer and read the immediate verdict
+///
+/// The answer is POSTed as `{"answer": "..."}` to `verify_path_template`
+/// (default `/api/captcha/verify/{challenge_id}`), which replies with `{"verified": bool}`
+/// and the challenge's `expected_tokens`, bound like a wait result.
+#[allow(clippy::too_many_arguments)]
+fn submit_answer(
+    client: &impl HttpClient,
+    launchpad_url: &str,
+    challenge_id: &str,
+    answer: &str,
+    input: &Input,
+    api_token: Option<&str>,
+    response_secret: Option<&str>,
+    run: &Run,
+) -> Result<(bool, Option<CaptchaErrorType>), Box<dyn std::error::Error>> {
+    run.log(LogLevel::Info, "📤 Submitting pre-collected answer...");
+    let template = input.verify_path_template.as_deref().unwrap_or(DEFAULT_VERIFY_PATH_TEMPLATE);
+    let verify_url = challenge_url(launchpad_url, template, challenge_id);
+    let verify_body = serde_json::json!({ "answer": answer }).to_string();
+    let verify_data: SubmitResponse =
+        post_json(client, &verify_url, &verify_body, "answer submit", api_token, response_secret, run)?;
+    check_binding(input, verify_data.expected_tokens.as_deref())?;
+
+    if verify_data.verified {
+        run.log(LogLevel::Info, "✅ CAPTCHA verified successfully!");
+        Ok((true, None))
+    } else {
+        run.log(LogLevel::Info, "❌ CAPTCHA verification failed (wrong answer)");
+        Ok((false, Some(CaptchaErrorType::WrongAnswer)))
+    }
+}
+
+/// POST a JSON body to the launchpad and parse its signed JSON reply
+fn post_json<T: DeserializeOwned>(
+    client: &impl HttpClient,
+    url: &str,
+    body: &str,
+    what: &str,
+    api_token: Option<&str>,
+    response_secret: Option<&str>,
+    run: &Run,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let request = HttpRequest::new(Method::Post, url, run.connect).json_body(body);
+    let response = send_following_redirects(client, with_auth(request, api_token), run)?;
+
+    let status = response.status();
+    if status == 401 || status == 403 {
+        return Err(VerifyError::auth(status).into());
+    }
+    if status == 429 {
+        return Err(VerifyError::rate_limited().into());
+    }
+    if !(200..300).contains(&status) {
+        return Err(format!("Launchpad {} failed. Status: {}", what, status).into());
+    }
+
+    let content_type = response.header("content-type");
+    let signature = response.header("x-signature");
+    let response_body = read_body(response, run)?;
+
+    // A submit verdict decides the purchase just like a wait result
+    check_signature(response_secret, signature.as_deref(), &response_body)?;
+
+    parse_json_body(what, content_type.as_deref(), &response_body)
+}
+
+/// Find a nonce such that `sha256(prefix || nonce)` has `difficulty` leading
+/// zero bits, where `nonce` is its decimal string. Returns `None` once
+/// `max_attempts` hashes are tried or `deadline` passes.
+fn find_pow_nonce(prefix: &str, difficulty: u32, deadline: Instant, max_attempts: u64) -> Option<u64> {
+    for nonce in 0..max_attempts {
+        // Checking the clock on every hash is wasteful; every 4096 is plenty
+        if nonce % 4096 == 0 && Instant::now() >= deadline {
+            return None;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(prefix.as_bytes());
+        hasher.update(nonce.to_string().as_bytes());
+        if leading_zero_bits(&hasher.finalize()) >= difficulty {
+            return Some(nonce);
+        }
+    }
+    None
+}
+
+/// Count leading zero bits of a hash
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Canned launchpad response
+    struct MockResponse {
+        status: u16,
+        content_type: &'static str,
+        body: Option<Vec<u8>>,
+        signature: Option<String>,
+        location: Option<String>,
+        /// Time the body takes to arrive
+        delay: Duration,
+    }
+
+    impl MockResponse {
+        fn json(status: u16, body: &str) -> Self {
+            Self {
+                status,
+                content_type: "application/json",
+                body: Some(body.as_bytes().to_vec()),
+                signature: None,
+                location: None,
+                delay: Duration::ZERO,
+            }
+        }
+
+        fn redirect(location: &str) -> Self {
+            Self {
+                status: 301,
+                content_type: "application/json",
+                body: None,
+                signature: None,
+                location: Some(location.to_string()),
+                delay: Duration::ZERO,
+            }
+        }
+
+        /// A 200 HTML page, as served by a misconfigured proxy
+        fn html(body: &str) -> Self {
+            Self {
+                content_type: "text/html; charset=utf-8",
+                ..Self::json(200, body)
+            }
+        }
+
+        fn signed(mut self, signature: &str) -> Self {
+            self.signature = Some(signature.to_string());
+            self
+        }
+
+        fn slow(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+    }
+
+    impl HttpResponse for MockResponse {
+        fn status(&self) -> u16 {
+            self.status
+        }
+
+        fn header(&self, name: &str) -> Option<String> {
+            match name {
+                "content-type" => Some(self.content_type.to_string()),
+                "x-signature" => self.signature.clone(),
+                "location" => self.location.clone(),
+                _ => None,
+            }
+        }
+
+        fn chunk(&mut self, _max_len: u64) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+            std::thread::sleep(self.delay);
+            Ok(self.body.take())
+        }
+    }
+
+    /// Replays queued responses in order and records every request
+    #[derive(Default)]
+    struct MockClient {
+        responses: RefCell<VecDeque<Result<MockResponse, String>>>,
+        requests: RefCell<Vec<HttpRequest>>,
+    }
+
+    impl MockClient {
+        fn new(responses: Vec<Result<MockResponse, String>>) -> Self {
+            Self {
+                responses: RefCell::new(responses.into()),
+                requests: RefCell::default(),
+            }
+        }
+    }
+
+    impl HttpClient for MockClient {
+        type Response = MockResponse;
+
+        fn send(&self, request: &HttpRequest) -> Result<MockResponse, Box<dyn std::error::Error>> {
+            self.requests.borrow_mut().push(request.clone());
+            self.responses
+                .borrow_mut()
+                .pop_front()
+                .expect("unexpected request")
+                .map_err(Into::into)
+        }
+    }
+
+    fn input() -> Input {
+        serde_json::from_value(serde_json::json!({
+            "session_id": "s1",
+            "buyer": "alice.testnet",
+            "amount": "1000000000000000000000000",
+            "launchpad_url": "https://launchpad.example",
+        }))
+        .unwrap()
+    }
+
+    fn challenge() -> Result<MockResponse, String> {
+        Ok(MockResponse::json(200, r#"{"challenge_id":"c1"}"#))
+    }
+
+    fn wait(status: &str, verified: bool) -> Result<MockResponse, String> {
+        Ok(MockResponse::json(
+            200,
+            &format!(r#"{{"status":"{}","verified":{}}}"#, status, verified),
+        ))
+    }
+
+    #[test]
+    fn solved_challenge_is_verified() {
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(output.verified);
+        assert_eq!(output.status, "verified");
+        assert_eq!(output.error_type, None);
+        assert_eq!(output.attempts, Some(2));
+        assert_eq!(output.served_by.as_deref(), Some("https://launchpad.example"));
+
+        let requests = client.requests.borrow();
+        assert_eq!(requests[0].method, Method::Post);
+        assert_eq!(requests[0].url, "https://launchpad.example/api/captcha/challenge");
+        assert!(requests[1].url.starts_with("https://launchpad.example/api/captcha/wait/c1?timeout="));
+    }
+
+    #[test]
+    fn presolved_challenge_skips_the_wait() {
+        let presolved = |verified: bool| {
+            let body = format!(r#"{{"challenge_id":"c1","status":"solved","verified":{}}}"#, verified);
+            let client = MockClient::new(vec![Ok(MockResponse::json(200, &body))]);
+            let output = verify(&input(), &client).unwrap();
+            assert_eq!(client.requests.borrow().len(), 1);
+            output
+        };
+
+        let output = presolved(true);
+        assert!(output.verified);
+        assert_eq!(output.attempts, Some(1));
+        assert_eq!(presolved(false).error_type, Some(CaptchaErrorType::WrongAnswer));
+
+        // Unsigned verdicts are not trusted once responses must be signed
+        let challenge: ChallengeResponse =
+            serde_json::from_str(r#"{"challenge_id":"c1","status":"solved","verified":true}"#).unwrap();
+        assert_eq!(presolved_result(&challenge, None), Some((true, None)));
+        assert_eq!(presolved_result(&challenge, Some("secret")), None);
+    }
+
+    #[test]
+    fn custom_endpoint_paths_are_used() {
+        let mut input = input();
+        input.challenge_path = Some("/v2/challenges".to_string());
+        input.wait_path_template = Some("/v2/challenges/{challenge_id}/wait?mode=long".to_string());
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+
+        assert!(verify(&input, &client).unwrap().verified);
+        let requests = client.requests.borrow();
+        assert_eq!(requests[0].url, "https://launchpad.example/v2/challenges");
+        assert!(requests[1]
+            .url
+            .starts_with("https://launchpad.example/v2/challenges/c1/wait?mode=long&timeout="));
+    }
+
+    #[test]
+    fn endpoint_paths_are_validated() {
+        let rejects = |challenge_path: Option<&str>, wait_path_template: Option<&str>| {
+            let mut input = input();
+            input.challenge_path = challenge_path.map(str::to_string);
+            input.wait_path_template = wait_path_template.map(str::to_string);
+            let client = MockClient::new(vec![]);
+            verify(&input, &client).err().map(|e| e.message).unwrap_or_default()
+        };
+
+        assert!(rejects(None, Some("/wait/{id}")).contains("must contain {challenge_id}"));
+        assert!(rejects(None, Some("wait/{challenge_id}")).contains("must start with '/'"));
+
+        let mut input = input();
+        input.pow_path_template = Some("/pow".to_string());
+        let error = verify(&input, &MockClient::new(vec![])).err().unwrap();
+        assert!(error.message.contains("pow_path_template \"/pow\" must contain {challenge_id}"), "{}", error);
+        assert!(rejects(Some("api/challenge"), None).starts_with("Invalid input: challenge_path"));
+    }
+
+    #[test]
+    fn solved_result_must_echo_expected_tokens() {
+        let mut input = input();
+        input.expected_tokens = Some("100".to_string());
+        let solved = |body: &str| {
+            let client = MockClient::new(vec![challenge(), Ok(MockResponse::json(200, body))]);
+            let output = verify(&input, &client).unwrap();
+            let requests = client.requests.borrow();
+            let challenge_body: serde_json::Value = serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+            assert_eq!(challenge_body["expected_tokens"], "100");
+            assert!(requests[1].url.contains("/wait/c1?expected_tokens=100&timeout="));
+            output
+        };
+
+        assert!(solved(r#"{"status":"solved","verified":true,"expected_tokens":"100"}"#).verified);
+        for body in [
+            r#"{"status":"solved","verified":true,"expected_tokens":"10"}"#,
+            r#"{"status":"solved","verified":true}"#,
+        ] {
+            let output = solved(body);
+            assert!(!output.verified);
+            assert_eq!(output.error_type, Some(CaptchaErrorType::BindingMismatch));
+        }
+
+        // Pre-solved verdicts are bound too
+        let client = MockClient::new(vec![Ok(MockResponse::json(
+            200,
+            r#"{"challenge_id":"c1","status":"solved","verified":true,"expected_tokens":"10"}"#,
+        ))]);
+        assert_eq!(verify(&input, &client).unwrap().error_type, Some(CaptchaErrorType::BindingMismatch));
+    }
+
+    #[test]
+    fn submitted_verdicts_must_echo_expected_tokens() {
+        let submitted = |answer: Option<&str>, challenge_body: &str, reply: &str| {
+            let mut input = input();
+            input.expected_tokens = Some("100".to_string());
+            input.answer = answer.map(str::to_string);
+            let client = MockClient::new(vec![
+                Ok(MockResponse::json(200, challenge_body)),
+                Ok(MockResponse::json(200, reply)),
+            ]);
+            verify(&input, &client).unwrap()
+        };
+        let answered = |reply: &str| submitted(Some("42"), r#"{"challenge_id":"c1"}"#, reply);
+        let pow = |reply: &str| {
+            submitted(None, r#"{"challenge_id":"c1","provider":"pow","prefix":"p","difficulty":1}"#, reply)
+        };
+
+        assert!(answered(r#"{"verified":true,"expected_tokens":"100"}"#).verified);
+        assert!(pow(r#"{"verified":true,"expected_tokens":"100"}"#).verified);
+        for reply in [r#"{"verified":true,"expected_tokens":"10"}"#, r#"{"verified":true}"#] {
+            assert_eq!(answered(reply).error_type, Some(CaptchaErrorType::BindingMismatch));
+            assert_eq!(pow(reply).error_type, Some(CaptchaErrorType::BindingMismatch));
+        }
+    }
+
+    #[test]
+    fn custom_verify_path_is_used() {
+        let mut input = input();
+        input.answer = Some("42".to_string());
+        input.verify_path_template = Some("/v2/answers/{challenge_id}".to_string());
+        let client = MockClient::new(vec![challenge(), Ok(MockResponse::json(200, r#"{"verified":true}"#))]);
+
+        assert!(verify(&input, &client).unwrap().verified);
+        assert_eq!(client.requests.borrow()[1].url, "https://launchpad.example/v2/answers/c1");
+
+        input.verify_path_template = Some("/v2/answers".to_string());
+        let error = verify(&input, &MockClient::new(vec![])).err().unwrap();
+        assert!(error.message.contains("verify_path_template \"/v2/answers\" must contain {challenge_id}"), "{}", error);
+    }
+
+    #[test]
+    fn found_pow_nonce_meets_the_difficulty() {
+        let far = Instant::now() + Duration::from_secs(60);
+
+        let nonce = find_pow_nonce("prefix", 12, far, 1_000_000).unwrap();
+        let hash = Sha256::digest(format!("prefix{}", nonce).as_bytes());
+        assert!(leading_zero_bits(&hash) >= 12);
+
+        // Out of attempts, or out of time
+        assert_eq!(find_pow_nonce("prefix", 64, far, 1_000), None);
+        assert_eq!(find_pow_nonce("prefix", 1, Instant::now(), 1_000), None);
+    }
+
+    fn pow_challenge() -> Result<MockResponse, String> {
+        Ok(MockResponse::json(200, r#"{"challenge_id":"c1","provider":"pow","prefix":"p","difficulty":4}"#))
+    }
+
+    #[test]
+    fn pow_nonce_is_submitted_and_judged() {
+        let submitted = |reply: &str| {
+            let client = MockClient::new(vec![pow_challenge(), Ok(MockResponse::json(200, reply))]);
+            let output = verify(&input(), &client).unwrap();
+            let requests = client.requests.borrow();
+            assert_eq!(requests[1].url, "https://launchpad.example/api/captcha/pow/c1");
+            let body: serde_json::Value = serde_json::from_slice(requests[1].body.as_ref().unwrap()).unwrap();
+            let nonce = body["nonce"].as_str().unwrap();
+            assert!(leading_zero_bits(&Sha256::digest(format!("p{}", nonce).as_bytes())) >= 4);
+            output
+        };
+
+        assert!(submitted(r#"{"verified":true}"#).verified);
+        let output = submitted(r#"{"verified":false}"#);
+        assert!(!output.verified);
+        assert_eq!(output.error_type, Some(CaptchaErrorType::WrongAnswer));
+    }
+
+    #[test]
+    fn pow_search_out_of_budget_is_a_timeout() {
+        let input = input();
+        let mut run = Run::from_input(&input);
+        run.deadline = Instant::now();
+        let challenge: ChallengeResponse =
+            serde_json::from_str(r#"{"challenge_id":"c1","provider":"pow","prefix":"p","difficulty":256}"#).unwrap();
+        let client = MockClient::default();
+
+        let result = solve_pow_challenge(&client, "https://launchpad.example", &challenge, &input, None, None, &run);
+
+        assert_eq!(result.unwrap(), (false, Some(CaptchaErrorType::Timeout)));
+        assert!(client.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn custom_pow_path_is_used() {
+        let mut input = input();
+        input.pow_path_template = Some("/v2/pow/{challenge_id}/nonce".to_string());
+        let client = MockClient::new(vec![pow_challenge(), Ok(MockResponse::json(200, r#"{"verified":true}"#))]);
+
+        assert!(verify(&input, &client).unwrap().verified);
+        assert_eq!(client.requests.borrow()[1].url, "https://launchpad.example/v2/pow/c1/nonce");
+    }
+
+    #[test]
+    fn oversized_body_is_rejected() {
+        let body = format!(r#"{{"challenge_id":"{}"}}"#, "c".repeat(MAX_BODY_SIZE));
+        let client = MockClient::new(vec![Ok(MockResponse::json(200, &body))]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert_eq!(output.error_type, Some(CaptchaErrorType::SystemError));
+        assert!(output.error.unwrap().contains("exceeds 65536 bytes"));
+    }
+
+    #[test]
+    fn slow_body_is_a_read_timeout() {
+        let mut run = Run::from_input(&input());
+        run.read = Duration::from_millis(20);
+        let response = MockResponse::json(200, r#"{"status":"solved","verified":true}"#).slow(Duration::from_millis(50));
+
+        let error = read_body(response, &run).unwrap_err();
+
+        let error = error.downcast_ref::<VerifyError>().unwrap();
+        assert_eq!(error.error_type, CaptchaErrorType::NetworkError);
+        assert!(error.message.contains("Read timeout"), "{}", error);
+    }
+
+    #[test]
+    fn wait_request_uses_the_connect_timeout() {
+        let mut input = input();
+        input.connect_timeout_secs = Some(3);
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+
+        verify(&input, &client).unwrap();
+
+        let requests = client.requests.borrow();
+        assert!(requests.iter().all(|request| request.connect_timeout == Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn empty_or_truncated_json_is_a_system_error() {
+        let waited = |body: &str| {
+            let client = MockClient::new(vec![challenge(), Ok(MockResponse::json(200, body))]);
+            let output = verify(&input(), &client).unwrap();
+            assert!(!output.verified);
+            assert_eq!(output.error_type, Some(CaptchaErrorType::SystemError));
+            output.error.unwrap()
+        };
+
+        assert!(waited("").contains("Empty verify response from launchpad"));
+        let error = waited(r#"{"status":"solved","verif"#);
+        assert!(error.contains("Invalid verify response JSON"), "{}", error);
+        assert!(error.ends_with(r#": {"status":"solved","verif"#), "{}", error);
+    }
+
+    #[test]
+    fn html_responses_are_rejected_with_a_preview() {
+        let page = format!("<html><title>Just a moment...</title>{}</html>", "x".repeat(500));
+        for responses in [
+            vec![Ok(MockResponse::html(&page))],
+            vec![challenge(), Ok(MockResponse::html(&page))],
+        ] {
+            let client = MockClient::new(responses);
+
+            let output = verify(&input(), &client).unwrap();
+
+            assert_eq!(output.error_type, Some(CaptchaErrorType::SystemError));
+            let error = output.error.unwrap();
+            assert!(error.contains("content type 'text/html; charset=utf-8'"), "{}", error);
+            assert!(error.contains("<html><title>Just a moment...</title>"), "{}", error);
+            assert!(error.len() < page.len(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn challenge_id_is_encoded_in_the_wait_url() {
+        for (challenge_id, encoded) in [("../admin", "..%2Fadmin"), ("c 1\n", "c%201%0A")] {
+            let body = serde_json::json!({ "challenge_id": challenge_id }).to_string();
+            let client = MockClient::new(vec![Ok(MockResponse::json(200, &body)), wait("solved", true)]);
+
+            assert!(verify(&input(), &client).unwrap().verified);
+            let requests = client.requests.borrow();
+            let prefix = format!("https://launchpad.example/api/captcha/wait/{}?timeout=", encoded);
+            assert!(requests[1].url.starts_with(&prefix), "{}", requests[1].url);
+        }
+    }
+
+    #[test]
+    fn empty_or_oversized_challenge_id_is_rejected() {
+        for challenge_id in [String::new(), "c".repeat(MAX_CHALLENGE_ID_LEN + 1)] {
+            let body = serde_json::json!({ "challenge_id": challenge_id }).to_string();
+            let client = MockClient::new(vec![Ok(MockResponse::json(200, &body))]);
+
+            let output = verify(&input(), &client).unwrap();
+
+            assert_eq!(output.error_type, Some(CaptchaErrorType::SystemError));
+            assert!(output.error.unwrap().contains("challenge_id"));
+            assert_eq!(client.requests.borrow().len(), 1);
+        }
+    }
+
+    #[test]
+    fn launchpad_redirect_is_followed() {
+        let client = MockClient::new(vec![
+            Ok(MockResponse::redirect("/v2/api/captcha/challenge")),
+            challenge(),
+            wait("solved", true),
+        ]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(output.verified);
+        assert_eq!(output.attempts, Some(3));
+        let requests = client.requests.borrow();
+        assert_eq!(requests[1].url, "https://launchpad.example/v2/api/captcha/challenge");
+    }
+
+    #[test]
+    fn bad_redirects_report_redirect_error() {
+        let downgrade = MockClient::new(vec![Ok(MockResponse::redirect("http://launchpad.example/api"))]);
+        let output = verify(&input(), &downgrade).unwrap();
+        assert_eq!(output.error_type, Some(CaptchaErrorType::RedirectError));
+
+        let endless = MockClient::new((0..=MAX_REDIRECTS).map(|_| Ok(MockResponse::redirect("/loop"))).collect());
+        let output = verify(&input(), &endless).unwrap();
+        assert_eq!(output.error_type, Some(CaptchaErrorType::RedirectError));
+        assert!(output.error.unwrap().contains("Too many redirects"));
+    }
+
+    #[test]
+    fn multi_step_captcha_waits_on_each_step() {
+        let client = MockClient::new(vec![
+            challenge(),
+            Ok(MockResponse::json(200, r#"{"status":"solved","verified":true,"next_challenge_id":"c2"}"#)),
+            wait("solved", true),
+        ]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(output.verified);
+        let requests = client.requests.borrow();
+        assert!(requests[1].url.contains("/wait/c1"));
+        assert!(requests[2].url.contains("/wait/c2"));
+    }
+
+    #[test]
+    fn multi_step_captcha_fails_on_any_failed_step() {
+        let client = MockClient::new(vec![
+            challenge(),
+            Ok(MockResponse::json(200, r#"{"status":"solved","verified":true,"next_challenge_id":"c2"}"#)),
+            wait("timeout", false),
+        ]);
+        let output = verify(&input(), &client).unwrap();
+        assert!(!output.verified);
+        assert_eq!(output.error_type, Some(CaptchaErrorType::Timeout));
+
+        let step = r#"{"status":"solved","verified":true,"next_challenge_id":"cN"}"#;
+        let mut responses = vec![challenge()];
+        responses.extend((0..MAX_CAPTCHA_STEPS).map(|_| Ok(MockResponse::json(200, step))));
+        let output = verify(&input(), &MockClient::new(responses)).unwrap();
+        assert_eq!(output.error_type, Some(CaptchaErrorType::SystemError));
+    }
+
+    #[test]
+    fn missing_challenge_on_wait_is_expired() {
+        let client = MockClient::new(vec![challenge(), Ok(MockResponse::json(404, r#"{"error":"not found"}"#))]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(!output.verified);
+        assert_eq!(output.status, "expired");
+        assert_eq!(output.error_type, Some(CaptchaErrorType::Expired));
+    }
+
+    #[test]
+    fn difficulty_is_forwarded_to_challenge() {
+        let body = |input: &Input| {
+            let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+            verify(input, &client).unwrap();
+            let requests = client.requests.borrow();
+            serde_json::from_slice::<serde_json::Value>(requests[0].body.as_ref().unwrap()).unwrap()
+        };
+
+        assert!(body(&input()).get("difficulty").is_none());
+        let mut input = input();
+        input.difficulty = Some(3);
+        assert_eq!(body(&input)["difficulty"], 3);
+    }
+
+    #[test]
+    fn sale_id_is_forwarded_to_challenge() {
+        let mut input = input();
+        input.sale_id = Some("seed-round".to_string());
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+
+        verify(&input, &client).unwrap();
+
+        let requests = client.requests.borrow();
+        let body: serde_json::Value = serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+        assert_eq!(body["sale_id"], "seed-round");
+    }
+
+    #[test]
+    fn challenge_nonce_is_stable_per_purchase() {
+        let nonce = |input: &Input| {
+            let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+            verify(input, &client).unwrap();
+            let requests = client.requests.borrow();
+            let body: serde_json::Value = serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+            body["nonce"].as_str().unwrap().to_string()
+        };
+
+        let first = nonce(&input());
+        assert_eq!(first.len(), 64);
+        assert_eq!(nonce(&input()), first);
+        assert_eq!(first, challenge_nonce(&input()));
+
+        let mut other = input();
+        other.amount = "2000000000000000000000000".to_string();
+        assert_ne!(nonce(&other), first);
+        let mut other = input();
+        other.session_id = "s2".to_string();
+        assert_ne!(nonce(&other), first);
+    }
+
+    #[test]
+    fn client_fingerprint_is_forwarded_only_when_set() {
+        let body = |input: &Input| {
+            let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+            verify(input, &client).unwrap();
+            let requests = client.requests.borrow();
+            serde_json::from_slice::<serde_json::Value>(requests[0].body.as_ref().unwrap()).unwrap()
+        };
+
+        let plain = body(&input());
+        assert!(plain.get("client_ip").is_none());
+        assert!(plain.get("user_agent").is_none());
+
+        let mut input = input();
+        input.client_ip = Some("203.0.113.7".to_string());
+        input.user_agent = Some("Mozilla/5.0".to_string());
+        let body = body(&input);
+        assert_eq!(body["client_ip"], "203.0.113.7");
+        assert_eq!(body["user_agent"], "Mozilla/5.0");
+        assert_eq!(body["session_id"], "s1");
+    }
+
+    #[test]
+    fn challenge_type_defaults_to_image_and_is_reported() {
+        let mut input = input();
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+        let output = verify(&input, &client).unwrap();
+        assert_eq!(output.challenge_type.as_deref(), Some("image"));
+
+        input.challenge_type = Some("audio".to_string());
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+        let output = verify(&input, &client).unwrap();
+
+        assert!(output.verified);
+        assert_eq!(output.challenge_type.as_deref(), Some("audio"));
+        let requests = client.requests.borrow();
+        let body: serde_json::Value = serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+        assert_eq!(body["challenge_type"], "audio");
+    }
+
+    #[test]
+    fn unknown_challenge_type_is_invalid_input() {
+        let mut input = input();
+        input.challenge_type = Some("video".to_string());
+        let client = MockClient::new(vec![]);
+
+        let error = verify(&input, &client).unwrap_err();
+
+        assert_eq!(error.error_type, CaptchaErrorType::SystemError);
+        assert!(client.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn challenge_retries_share_one_idempotency_key() {
+        let mut input = input();
+        input.fallback_launchpad_urls = vec!["https://backup.example".to_string()];
+        let client = MockClient::new(vec![
+            Ok(MockResponse::json(503, "{}")),
+            challenge(),
+            wait("solved", true),
+        ]);
+
+        let output = verify(&input, &client).unwrap();
+        assert_eq!(output.served_by.as_deref(), Some("https://backup.example"));
+
+        let key = |request: &HttpRequest| {
+            request
+                .headers
+                .iter()
+                .find(|(name, _)| name == "Idempotency-Key")
+                .map(|(_, value)| value.clone())
+        };
+        let requests = client.requests.borrow();
+        assert_eq!(key(&requests[0]), Some(default_idempotency_key("s1")));
+        assert_eq!(key(&requests[0]), key(&requests[1]));
+        assert_eq!(key(&requests[2]), None);
+    }
+
+    #[test]
+    fn receipt_is_hmac_of_result_fields() {
+        let receipt = verification_receipt("secret", &input(), 1_000_000_000_000_000_000_000_000, true, 1_700_000_000);
+
+        assert_eq!(receipt, "6069a9333831655ff65d66dd90c40ae90c5b6433dd33e561690b1e03ec6a75cd");
+        assert_ne!(
+            receipt,
+            verification_receipt("secret", &input(), 1_000_000_000_000_000_000_000_000, false, 1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn verify_response_signature_is_checked_when_secret_is_set() {
+        let body = r#"{"status":"solved","verified":true}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body.as_bytes());
+        let signature = to_hex(&mac.finalize().into_bytes());
+        let run = Run::from_input(&input());
+        let wait = |response: MockResponse, secret: Option<&str>| {
+            let client = MockClient::new(vec![Ok(response)]);
+            wait_for_solution(&client, "https://launchpad.example/api/captcha/wait/c1", None, secret, &run)
+        };
+
+        assert!(wait(MockResponse::json(200, body).signed(&signature), Some("secret")).unwrap().verified);
+        // Unsigned backends still work when no secret is configured
+        assert!(wait(MockResponse::json(200, body), None).unwrap().verified);
+
+        for response in [
+            MockResponse::json(200, body),
+            MockResponse::json(200, body).signed(&signature[2..]),
+            MockResponse::json(200, body).signed("not hex"),
+            MockResponse::json(200, r#"{"status":"solved","verified":false}"#).signed(&signature),
+        ] {
+            let error = wait(response, Some("secret")).err().unwrap();
+            assert_eq!(error.downcast_ref::<VerifyError>().unwrap().error_type, CaptchaErrorType::SignatureInvalid);
+        }
+    }
+
+    #[test]
+    fn submit_response_signature_is_checked_when_secret_is_set() {
+        let body = r#"{"verified":true}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body.as_bytes());
+        let signature = to_hex(&mac.finalize().into_bytes());
+        let input = input();
+        let run = Run::from_input(&input);
+        let submit = |response: MockResponse| {
+            let client = MockClient::new(vec![Ok(response)]);
+            submit_answer(&client, "https://launchpad.example", "c1", "42", &input, None, Some("secret"), &run)
+        };
+
+        assert_eq!(submit(MockResponse::json(200, body).signed(&signature)).unwrap(), (true, None));
+        for response in [
+            MockResponse::json(200, body),
+            MockResponse::json(200, r#"{"verified":false}"#).signed(&signature),
+        ] {
+            let error = submit(response).err().unwrap();
+            assert_eq!(error.downcast_ref::<VerifyError>().unwrap().error_type, CaptchaErrorType::SignatureInvalid);
+        }
+    }
+
+    #[test]
+    fn log_level_defaults_to_info() {
+        let level = |log_level: Option<&str>| {
+            let mut input = input();
+            input.log_level = log_level.map(str::to_string);
+            Run::from_input(&input).log_level
+        };
+        assert_eq!(level(None), LogLevel::Info);
+        assert_eq!(level(Some("error")), LogLevel::Error);
+        assert_eq!(level(Some("debug")), LogLevel::Debug);
+        assert_eq!(level(Some("verbose")), LogLevel::Info);
+        assert!(LogLevel::Error < LogLevel::Info && LogLevel::Info < LogLevel::Debug);
+    }
+
+    #[test]
+    fn error_type_wire_names_match_as_str() {
+        for error_type in [
+            CaptchaErrorType::Timeout,
+            CaptchaErrorType::WrongAnswer,
+            CaptchaErrorType::RateLimited,
+            CaptchaErrorType::NetworkError,
+            CaptchaErrorType::AuthError,
+            CaptchaErrorType::SignatureInvalid,
+            CaptchaErrorType::Expired,
+            CaptchaErrorType::BindingMismatch,
+            CaptchaErrorType::RedirectError,
+            CaptchaErrorType::SystemError,
+            CaptchaErrorType::Unknown,
+        ] {
+            assert_eq!(serde_json::to_value(error_type).unwrap(), error_type.as_str());
+        }
+        let parsed: CaptchaErrorType = serde_json::from_str(r#""from_a_newer_worker""#).unwrap();
+        assert_eq!(parsed, CaptchaErrorType::Unknown);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_lengths() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn poll_interval_is_clamped_and_polls_fit_the_budget() {
+        let settings = |poll_interval_ms: Option<u64>, max_polls: Option<u32>| {
+            let mut input = input();
+            input.poll_interval_ms = poll_interval_ms;
+            input.max_polls = max_polls;
+            poll_settings(&input)
+        };
+        let ms = Duration::from_millis;
+
+        // 38s budget / interval
+        assert_eq!(settings(None, None), (ms(2_000), 19));
+        assert_eq!(settings(Some(100), None), (ms(250), 152));
+        assert_eq!(settings(Some(60_000), None), (ms(10_000), 3));
+        assert_eq!(settings(Some(5_000), None), (ms(5_000), 7));
+
+        assert_eq!(settings(Some(5_000), Some(2)), (ms(5_000), 2));
+        assert_eq!(settings(Some(5_000), Some(100)), (ms(5_000), 7));
+        assert_eq!(settings(Some(5_000), Some(0)), (ms(5_000), 1));
+    }
+
+    #[test]
+    fn backoff_delays_grow_within_bounds() {
+        let run = Run::from_input(&input());
+        let ceilings: Vec<u64> = (0..8).map(|attempt| backoff_ceiling_ms(attempt, 100, 5_000)).collect();
+        assert_eq!(ceilings, [100, 200, 400, 800, 1_600, 3_200, 5_000, 5_000]);
+        assert_eq!(backoff_ceiling_ms(200, 100, 5_000), 5_000);
+
+        for attempt in 0..8 {
+            let delay = run.backoff_delay(attempt, 100, 5_000);
+            assert!(delay <= Duration::from_millis(ceilings[attempt as usize]));
+            // Same session, same delay
+            assert_eq!(delay, Run::from_input(&input()).backoff_delay(attempt, 100, 5_000));
+        }
+
+        let mut other = input();
+        other.session_id = "s2".to_string();
+        let delays = |input: &Input| {
+            let run = Run::from_input(input);
+            (0..8).map(|attempt| run.backoff_delay(attempt, 100, 5_000)).collect::<Vec<_>>()
+        };
+        assert_ne!(delays(&input()), delays(&other));
+    }
+
+    #[test]
+    fn rate_limited_challenge_is_retried() {
+        let client = MockClient::new(vec![
+            Ok(MockResponse::json(429, "{}")),
+            challenge(),
+            wait("solved", true),
+        ]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(output.verified);
+        assert_eq!(output.attempts, Some(3));
+    }
+
+    #[test]
+    fn answer_is_submitted_without_waiting() {
+        let answered = |verified: bool| {
+            let mut input = input();
+            input.answer = Some("42".to_string());
+            let reply = format!(r#"{{"verified":{}}}"#, verified);
+            let client = MockClient::new(vec![challenge(), Ok(MockResponse::json(200, &reply))]);
+            let output = verify(&input, &client).unwrap();
+
+            let requests = client.requests.borrow();
+            assert_eq!(requests.len(), 2);
+            assert_eq!(requests[1].method, Method::Post);
+            assert_eq!(requests[1].url, "https://launchpad.example/api/captcha/verify/c1");
+            let body: serde_json::Value = serde_json::from_slice(requests[1].body.as_ref().unwrap()).unwrap();
+            assert_eq!(body["answer"], "42");
+            output
+        };
+
+        assert!(answered(true).verified);
+        let output = answered(false);
+        assert!(!output.verified);
+        assert_eq!(output.error_type, Some(CaptchaErrorType::WrongAnswer));
+    }
+
+    #[test]
+    fn unsupported_api_version_is_a_system_error() {
+        let client = MockClient::new(vec![Ok(MockResponse::json(200, r#"{"challenge_id":"c1","api_version":2}"#))]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert_eq!(output.error_type, Some(CaptchaErrorType::SystemError));
+        assert!(output.error.unwrap().contains("Unsupported launchpad API version 2"));
+        assert_eq!(client.requests.borrow().len(), 1);
+
+        // No api_version is v1, as is an explicit 1
+        for body in [r#"{"challenge_id":"c1"}"#, r#"{"challenge_id":"c1","api_version":1}"#] {
+            let client = MockClient::new(vec![Ok(MockResponse::json(200, body)), wait("solved", true)]);
+            assert!(verify(&input(), &client).unwrap().verified);
+        }
+    }
+
+    #[test]
+    fn dry_run_makes_no_requests() {
+        let dry_run = |session_id: &str| {
+            let mut input = input();
+            input.session_id = session_id.to_string();
+            input.dry_run = Some(true);
+            let client = MockClient::default();
+            let output = verify(&input, &client).unwrap();
+            assert!(client.requests.borrow().is_empty());
+            assert_eq!(output.attempts, Some(0));
+            output
+        };
+
+        let output = dry_run("s1");
+        assert!(output.verified);
+        assert_eq!(output.status, "verified");
+        assert_eq!(output.session_id, "s1");
+        assert_eq!(output.error_type, None);
+
+        let output = dry_run(DRY_RUN_FAIL_SESSION);
+        assert!(!output.verified);
+        assert_eq!(output.status, "wrong_answer");
+        assert_eq!(output.session_id, "dry-run-fail");
+        assert_eq!(output.error_type, Some(CaptchaErrorType::WrongAnswer));
+    }
+
+    #[test]
+    fn timed_out_challenge_reports_timeout() {
+        let client = MockClient::new(vec![challenge(), wait("timeout", false)]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(!output.verified);
+        assert_eq!(output.status, "timeout");
+        assert_eq!(output.error_type, Some(CaptchaErrorType::Timeout));
+    }
+
+    #[test]
+    fn rejected_solution_reports_wrong_answer() {
+        let client = MockClient::new(vec![challenge(), wait("solved", false)]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(!output.verified);
+        assert_eq!(output.error_type, Some(CaptchaErrorType::WrongAnswer));
+    }
+
+    #[test]
+    fn unreachable_launchpad_reports_network_error() {
+        let client = MockClient::new(vec![Err("connection refused".to_string())]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert!(!output.verified);
+        assert_eq!(output.error_type, Some(CaptchaErrorType::NetworkError));
+        assert!(output.error.unwrap().contains("connection refused"));
+        assert_eq!(output.attempts, Some(1));
+    }
+
+    #[test]
+    fn empty_or_malformed_input_yields_error_output() {
+        let garbage = String::from_utf8_lossy(&[0xff, 0x00, b'{', 0xc3]).into_owned();
+        for raw_input in ["", "{\"session_id\": \"s1\"", "not json", garbage.as_str()] {
+            let output = verify_json(raw_input, &MockClient::default());
+
+            let json: serde_json::Value =
+                serde_json::from_str(&serde_json::to_string(&output).unwrap()).unwrap();
+            assert_eq!(json["verified"], false);
+            assert_eq!(json["session_id"], "");
+            assert_eq!(json["error_type"], "system_error");
+            assert!(json["error"].as_str().unwrap().contains("Invalid input: malformed JSON"));
+            assert_eq!(json["worker_version"], worker_version());
+        }
+    }
+
+    #[test]
+    fn output_reports_worker_version() {
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        let version = output.worker_version.unwrap();
+        assert!(version.starts_with(env!("CARGO_PKG_VERSION")), "{}", version);
+    }
+
+    #[test]
+    fn non_numeric_amount_is_rejected_before_any_request() {
+        let mut input = input();
+        input.amount = "1 NEAR".to_string();
+        let client = MockClient::default();
+
+        let error = verify(&input, &client).err().unwrap();
+
+        assert_eq!(error.error_type, CaptchaErrorType::SystemError);
+        assert!(error.message.contains("amount \"1 NEAR\" is not a decimal number"), "{}", error);
+        assert!(client.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn empty_or_overflowing_amount_is_rejected_before_any_request() {
+        for (amount, reason) in [
+            ("", "amount is empty"),
+            ("340282366920938463463374607431768211456", "amount 340282366920938463463374607431768211456 overflows u128"),
+        ] {
+            let mut input = input();
+            input.amount = amount.to_string();
+            let client = MockClient::default();
+
+            let error = verify(&input, &client).err().unwrap();
+
+            assert_eq!(error.error_type, CaptchaErrorType::SystemError);
+            assert!(error.message.contains(reason), "{}", error);
+            assert!(client.requests.borrow().is_empty());
+        }
+    }
+
+    #[test]
+    fn amount_is_forwarded_verbatim() {
+        let mut input = input();
+        input.amount = "0001000".to_string();
+        let client = MockClient::new(vec![challenge(), wait("solved", true)]);
+
+        verify(&input, &client).unwrap();
+
+        let requests = client.requests.borrow();
+        let body: serde_json::Value = serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+        assert_eq!(body["amount"], "0001000");
+    }
+
+    #[test]
+    fn account_id_validation_follows_near_rules() {
+        let implicit = "98793cd91a3f870fb126f66285808c7e094afcfc4eda8a970f6648cdf0dbd6de";
+        for valid in [implicit, "alice.testnet", "a-b_c.near", "0x85f17cf997934a597031b2e18a9ab6ebd4b9f6a4", "ab"] {
+            assert!(validate_account_id("buyer", valid).is_ok(), "{}", valid);
+        }
+
+        let too_long = "a".repeat(65);
+        for invalid in ["a", too_long.as_str(), "Alice.near", ".alice", "alice.", "alice..near", "al ice.near", "a-_b"] {
+            let error = validate_account_id("buyer", invalid).unwrap_err();
+            assert_eq!(error.error_type, CaptchaErrorType::SystemError);
+            assert!(error.message.starts_with("Invalid input: buyer"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn malformed_buyer_is_rejected_before_any_request() {
+        let mut input = input();
+        input.buyer = "Not An Account".to_string();
+        let client = MockClient::default();
+
+        let error = verify(&input, &client).err().unwrap();
+
+        assert_eq!(error.error_type, CaptchaErrorType::SystemError);
+        assert!(error.message.contains("buyer"));
+        assert!(client.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn rejected_credentials_report_auth_error() {
+        let client = MockClient::new(vec![Ok(MockResponse::json(401, "{}"))]);
+
+        let output = verify(&input(), &client).unwrap();
+
+        assert_eq!(output.error_type, Some(CaptchaErrorType::AuthError));
+    }
+}
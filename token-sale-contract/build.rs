@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Embed the contract's git commit as `CONTRACT_GIT_COMMIT` (empty if git is unavailable)
+///
+/// A release build may set `CONTRACT_GIT_COMMIT` itself, e.g. when building
+/// from a source archive without `.git`; that value wins over git.
+fn main() {
+    println!("cargo:rerun-if-env-changed=CONTRACT_GIT_COMMIT");
+    let commit = match std::env::var("CONTRACT_GIT_COMMIT") {
+        Ok(commit) => commit,
+        Err(_) => {
+            watch_git_head();
+            git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_default()
+        }
+    };
+
+    println!("cargo:rustc-env=CONTRACT_GIT_COMMIT={}", commit);
+}
+
+/// Rebuild when HEAD moves: HEAD itself only changes on checkout, new commits
+/// on a branch update the branch's ref file (or `packed-refs`)
+fn watch_git_head() {
+    let Some(git_dir) = git(&["rev-parse", "--git-dir"]).map(PathBuf::from) else {
+        return;
+    };
+    let mut watched = vec![git_dir.join("HEAD"), git_dir.join("packed-refs")];
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        watched.push(git_dir.join(head_ref));
+    }
+    // A missing path would rerun the script on every build
+    for path in watched.iter().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+/// Trimmed stdout of a successful git command
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
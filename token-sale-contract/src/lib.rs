@@ -1,23 +1,127 @@
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::store::{IterableMap, IterableSet, LookupMap, LookupSet};
 use schemars::JsonSchema;
-use near_sdk::{env, ext_contract, log, near_bindgen, AccountId, Gas, NearToken, Promise, PromiseError};
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, BorshStorageKey, Gas, NearToken, Promise,
+    PromiseError,
+};
 
-/// Minimum purchase amount
-const MIN_PURCHASE: u128 = 100_000_000_000_000_000_000_000; // 0.1 NEAR
+/// Default minimum purchase amount
+const DEFAULT_MIN_PURCHASE: u128 = 100_000_000_000_000_000_000_000; // 0.1 NEAR
 
 /// Tokens per NEAR
 const TOKENS_PER_NEAR: u128 = 100; // 100 tokens per 1 NEAR
 
-/// Fixed gas for callback
-const CALLBACK_GAS: u64 = 10_000_000_000_000; // 10 TGas
+/// Reserved from the attached deposit for OutLayer execution
+const EXECUTION_RESERVE: u128 = 10_000_000_000_000_000_000_000; // 0.01 NEAR
+
+/// 1 NEAR in yoctoNEAR
+const ONE_NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Fixed gas for callback, including room to schedule the post-purchase hook
+const CALLBACK_GAS: u64 = 15_000_000_000_000; // 15 TGas
+
+/// Default gas reserved for OutLayer's `request_execution` call
+const DEFAULT_MIN_REQUEST_GAS: u64 = 100_000_000_000_000; // 100 TGas
+
+/// Most gas a transaction can attach; the request floor and the callback must fit in it
+const MAX_PREPAID_GAS: u64 = 300_000_000_000_000; // 300 TGas
+
+/// Gas for the simulated OutLayer call in `mock-captcha` builds
+#[cfg(feature = "mock-captcha")]
+const MOCK_REQUEST_GAS: u64 = 5_000_000_000_000; // 5 TGas
+
+/// Gas for the post-purchase hook call; the hook must do its work within it
+const HOOK_GAS: u64 = 3_000_000_000_000; // 3 TGas
 
 /// OutLayer contract ID
 /// For testnet: "outlayer.testnet"
 /// For mainnet: "outlayer.near"
+#[cfg_attr(feature = "mock-captcha", allow(dead_code))]
 const OUTLAYER_CONTRACT_ID: &str = "outlayer.testnet";
 
+/// Default worker repository in `code_source`
+const DEFAULT_WORKER_REPO: &str = "https://github.com/zavodil/captcha-ark";
+
+/// Default worker commit pinned in `code_source`
+const DEFAULT_WORKER_COMMIT: &str = "main";
+
+/// Build targets OutLayer can compile the worker for. The worker uses
+/// wasi-http, so only wasip2 builds actually run it today.
+const BUILD_TARGETS: [&str; 2] = ["wasm32-wasip2", "wasm32-wasip1"];
+const DEFAULT_BUILD_TARGET: &str = "wasm32-wasip2";
+
+/// OutLayer response formats; the callback parses a `CaptchaResponse` only from "Json"
+const RESPONSE_FORMATS: [&str; 3] = ["Json", "Text", "Bytes"];
+const DEFAULT_RESPONSE_FORMAT: &str = "Json";
+
+/// Default time after which the owner may force-refund a stuck purchase
+const DEFAULT_PENDING_TIMEOUT_NS: u64 = 10 * 60 * 1_000_000_000; // 10 minutes
+
+/// Bounds for the worker's re-poll interval
+const MIN_POLL_INTERVAL_MS: u64 = 250;
+const MAX_POLL_INTERVAL_MS: u64 = 10_000;
+
+/// NEP-297 event standard
+const EVENT_STANDARD: &str = "captcha-token-sale";
+const EVENT_VERSION: &str = "1.0.0";
+
+/// Purchase records kept per account; the oldest are dropped first
+const MAX_HISTORY_PER_ACCOUNT: usize = 50;
+
+/// Length of a daily cap window (UTC days)
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Highest CAPTCHA difficulty requested for accounts that keep failing
+const MAX_CAPTCHA_DIFFICULTY: u8 = 5;
+
+/// Maximum sale ID length in bytes
+const MAX_SALE_ID_LEN: usize = 64;
+
+/// Most decimals a sold token may have (NEAR's own precision)
+const MAX_TOKEN_DECIMALS: u8 = 24;
+
+/// Maximum memo length in bytes
+const MAX_MEMO_LEN: usize = 256;
+
+/// Maximum `client_ip` length in bytes (a full IPv6 address with zone fits)
+const MAX_CLIENT_IP_LEN: usize = 64;
+
+/// Maximum `user_agent` length in bytes
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// Maximum number of entries returned by paginated views
+const MAX_PAGE_LIMIT: u64 = 100;
+
+/// Maximum number of accounts accepted by batch views
+const MAX_BATCH_SIZE: usize = 50;
+
+/// Maximum number of expired purchases refunded by one `sweep_expired` call
+const MAX_SWEEP_LIMIT: u64 = 20;
+
+/// Default balance kept above the storage stake when sending NEAR out
+const DEFAULT_STORAGE_BUFFER: u128 = 50_000_000_000_000_000_000_000; // 0.05 NEAR
+
+/// Storage prefixes for persistent collections
+#[derive(BorshSerialize, BorshStorageKey)]
+#[borsh(crate = "near_sdk::borsh")]
+enum StorageKey {
+    Allowlist,
+    Pending,
+    History,
+    Buyers,
+    DiscountCodes,
+    UsedDiscounts,
+    FailureCounts,
+    Purchased,
+    PendingSessions,
+    LastPurchase,
+    RetiredSessions,
+    Blacklist,
+}
+
 /// External contract interface for OutLayer
 #[ext_contract(ext_outlayer)]
 #[allow(dead_code)]
@@ -33,26 +137,417 @@ trait OutLayer {
     );
 }
 
+/// Interface a post-purchase hook contract implements
+#[ext_contract(ext_hook)]
+#[allow(dead_code)]
+trait PostPurchaseHook {
+    fn on_token_purchase(&mut self, buyer: AccountId, tokens: U128);
+}
+
+/// Stand-in for OutLayer in `mock-captcha` builds
+#[cfg(feature = "mock-captcha")]
+#[ext_contract(ext_mock_outlayer)]
+#[allow(dead_code)]
+trait MockOutLayer {
+    fn mock_request_execution(&self, session_id: String) -> Option<CaptchaResponse>;
+}
+
 /// External contract interface for self callbacks
 #[ext_contract(ext_self)]
 #[allow(dead_code)]
 trait ExtSelf {
+    #[allow(clippy::too_many_arguments)]
     fn on_captcha_verified(
         &mut self,
+        session_id: String,
         buyer: AccountId,
         amount: NearToken,
-        #[callback_result] result: Result<Option<CaptchaResponse>, PromiseError>,
+        tokens: U128,
+        execution_fee: NearToken,
+        memo: Option<String>,
+        #[callback_result] result: Result<Option<near_sdk::serde_json::Value>, PromiseError>,
     ) -> String;
 }
 
 /// CAPTCHA verification response from WASM
+///
+/// Missing fields take their defaults, so only a response of the wrong shape
+/// is rejected by `parse_captcha_result`.
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct CaptchaResponse {
+    #[serde(default)]
     pub verified: bool,
+    /// "verified" or the error type; takes precedence over `verified`/`error_type`
+    /// (absent in older worker builds)
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
     pub session_id: String,
     pub error: Option<String>,
-    pub error_type: Option<String>, // "timeout", "wrong_answer", "network_error", "system_error"
+    pub error_type: Option<CaptchaErrorType>,
+    /// Verification duration (absent in older worker builds)
+    pub solve_latency_ms: Option<u64>,
+    /// HTTP requests made by the worker (absent in older worker builds)
+    pub attempts: Option<u32>,
+    /// Worker-signed HMAC receipt of the result, verifiable by the launchpad
+    /// (absent if the worker has no receipt secret)
+    #[serde(default)]
+    pub receipt: Option<String>,
+    /// Unix seconds covered by `receipt`
+    #[serde(default)]
+    pub receipt_timestamp: Option<u64>,
+    /// Worker package version, plus its commit when built with one
+    /// (absent in older worker builds)
+    #[serde(default)]
+    pub worker_version: Option<String>,
+}
+
+/// Failure reason reported by the worker as `error_type` (and `status`)
+///
+/// Mirrors `CaptchaErrorType` in the captcha-ark worker; the serialized
+/// names are the wire format, so a variant must be added to both. Values
+/// this build doesn't know deserialize as `Unknown`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum CaptchaErrorType {
+    Timeout,
+    WrongAnswer,
+    RateLimited,
+    NetworkError,
+    AuthError,
+    SignatureInvalid,
+    Expired,
+    BindingMismatch,
+    RedirectError,
+    SystemError,
+    #[serde(other)]
+    Unknown,
+}
+
+impl CaptchaErrorType {
+    /// Parse a wire name, such as a worker `status`
+    pub fn parse(value: &str) -> Self {
+        near_sdk::serde_json::from_value(near_sdk::serde_json::Value::from(value)).unwrap_or(CaptchaErrorType::Unknown)
+    }
+
+    /// Wire name, as used in `error_type`, history and logs
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaptchaErrorType::Timeout => "timeout",
+            CaptchaErrorType::WrongAnswer => "wrong_answer",
+            CaptchaErrorType::RateLimited => "rate_limited",
+            CaptchaErrorType::NetworkError => "network_error",
+            CaptchaErrorType::AuthError => "auth_error",
+            CaptchaErrorType::SignatureInvalid => "signature_invalid",
+            CaptchaErrorType::Expired => "expired",
+            CaptchaErrorType::BindingMismatch => "binding_mismatch",
+            CaptchaErrorType::RedirectError => "redirect_error",
+            CaptchaErrorType::SystemError => "system_error",
+            CaptchaErrorType::Unknown => "unknown",
+        }
+    }
+}
+
+/// Decision derived from the OutLayer callback result
+#[derive(Debug, PartialEq, Eq)]
+pub enum CaptchaOutcome {
+    /// Worker confirmed the CAPTCHA was solved
+    Verified,
+    /// Worker ran but verification failed, with its `error_type` (`Unknown` if absent)
+    Failed(CaptchaErrorType),
+    /// OutLayer returned no result (worker crashed, ran out of resources, etc.)
+    ExecutionFailed,
+    /// The promise itself failed
+    SystemError,
+}
+
+impl CaptchaOutcome {
+    /// Short label used in purchase history and logs
+    pub fn label(&self) -> &str {
+        match self {
+            CaptchaOutcome::Verified => "verified",
+            CaptchaOutcome::Failed(error_type) => error_type.as_str(),
+            CaptchaOutcome::ExecutionFailed => "execution_failed",
+            CaptchaOutcome::SystemError => "system_error",
+        }
+    }
+}
+
+/// Parse the raw OutLayer callback result into a `CaptchaResponse`
+///
+/// near-sdk would panic on a result that doesn't deserialize, leaving the
+/// purchase pending. Instead, a present but malformed response becomes a
+/// `system_error` failure, so the buyer is refunded.
+pub fn parse_captcha_result(
+    result: Result<Option<near_sdk::serde_json::Value>, PromiseError>,
+) -> Result<Option<CaptchaResponse>, PromiseError> {
+    result.map(|value| {
+        value.map(|value| {
+            near_sdk::serde_json::from_value(value.clone()).unwrap_or_else(|e| {
+                log!("⚠️ Malformed worker response ({}): {}", e, value);
+                CaptchaResponse {
+                    verified: false,
+                    status: Some("system_error".to_string()),
+                    session_id: String::new(),
+                    error: Some(format!("Malformed worker response: {}", e)),
+                    error_type: Some(CaptchaErrorType::SystemError),
+                    solve_latency_ms: None,
+                    attempts: None,
+                    receipt: None,
+                    receipt_timestamp: None,
+                    worker_version: None,
+                }
+            })
+        })
+    })
+}
+
+/// Interpret an OutLayer callback result
+///
+/// Reusable by any contract integrating captcha-ark, so the decision logic
+/// doesn't have to be copied from `on_captcha_verified`.
+pub fn interpret_captcha(result: &Result<Option<CaptchaResponse>, PromiseError>) -> CaptchaOutcome {
+    match result {
+        Ok(Some(CaptchaResponse { status: Some(status), .. })) if status == "verified" => {
+            CaptchaOutcome::Verified
+        }
+        Ok(Some(CaptchaResponse { status: Some(status), .. })) => {
+            CaptchaOutcome::Failed(CaptchaErrorType::parse(status))
+        }
+        // Older workers only report `verified` plus an optional `error_type`
+        Ok(Some(response)) if response.verified => CaptchaOutcome::Verified,
+        Ok(Some(response)) => {
+            CaptchaOutcome::Failed(response.error_type.unwrap_or(CaptchaErrorType::Unknown))
+        }
+        Ok(None) => CaptchaOutcome::ExecutionFailed,
+        Err(_) => CaptchaOutcome::SystemError,
+    }
+}
+
+/// Input passed to the captcha-ark worker as `input_data`
+///
+/// Serialized in field declaration order so the same purchase always
+/// produces the same string, whatever serde_json's map ordering.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WorkerInput<'a> {
+    pub session_id: &'a str,
+    /// Sale the purchase belongs to, so one launchpad can serve several sales
+    pub sale_id: &'a str,
+    pub buyer: &'a AccountId,
+    pub amount: U128,
+    /// Tokens the purchase yields, including any bonus; the launchpad must echo
+    /// it with a solved result so a cheaper purchase's solution can't be replayed
+    pub expected_tokens: U128,
+    pub launchpad_url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_interval_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answer: Option<&'a str>,
+    /// Extra CAPTCHA difficulty for an account that keeps failing (absent = normal)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u8>,
+    /// Client fingerprint from the front-end, for the launchpad's risk scoring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<&'a str>,
+}
+
+/// Settled purchase, as recorded in an account's history
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseRecord {
+    pub session_id: String,
+    /// Tokens credited (0 unless the purchase was verified)
+    #[schemars(with = "String")]
+    pub tokens: U128,
+    /// Purchase amount in yoctoNEAR
+    #[schemars(with = "String")]
+    pub near_amount: U128,
+    #[schemars(with = "String")]
+    pub timestamp_ns: U64,
+    /// "verified", a worker error type (e.g. "wrong_answer"), "execution_failed",
+    /// "system_error", "force_refunded", "swept" or "cancelled"
+    pub outcome: String,
+    /// Part of the deposit attached to OutLayer for execution, in yoctoNEAR
+    #[schemars(with = "String")]
+    pub execution_fee: U128,
+    /// yoctoNEAR returned to the buyer by this contract (0 for a verified purchase)
+    #[schemars(with = "String")]
+    pub refunded: U128,
+}
+
+/// Sale parameters, as returned by `get_config`
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleConfig {
+    /// Minimum purchase in yoctoNEAR, excluding the execution reserve
+    #[schemars(with = "String")]
+    pub min_purchase: U128,
+    /// Largest purchase in yoctoNEAR (None = unlimited)
+    #[schemars(with = "Option<String>")]
+    pub max_purchase: Option<U128>,
+    /// Purchases above this many yoctoNEAR are rejected (None = unlimited)
+    #[schemars(with = "Option<String>")]
+    pub max_near_per_tx: Option<U128>,
+    /// Part of the deposit attached to OutLayer, in yoctoNEAR
+    #[schemars(with = "String")]
+    pub execution_reserve: U128,
+    /// Price in whole tokens per NEAR; every token amount elsewhere is in the smallest unit
+    pub tokens_per_near: u64,
+    /// Decimals of the sold token; all token amounts are in its smallest unit (10^-decimals tokens)
+    pub token_decimals: u8,
+    pub allow_partial_fill: bool,
+    pub allowlist_enabled: bool,
+    pub worker_repo: String,
+    pub worker_commit: String,
+    pub build_target: String,
+    pub response_format: String,
+    #[schemars(with = "String")]
+    pub pending_timeout_ns: U64,
+    /// Gas always reserved for OutLayer's `request_execution` call
+    #[schemars(with = "String")]
+    pub min_request_gas: U64,
+    /// Tokens purchasable per UTC day (None = unlimited)
+    #[schemars(with = "Option<String>")]
+    pub daily_cap: Option<U128>,
+    /// Minimum time between an account's verified purchase and its next buy
+    #[schemars(with = "String")]
+    pub purchase_cooldown_ns: U64,
+    /// Contract notified of each verified purchase (None = no hook)
+    #[schemars(with = "Option<String>")]
+    pub post_purchase_hook: Option<AccountId>,
+    /// yoctoNEAR kept above the storage stake when refunding
+    #[schemars(with = "String")]
+    pub storage_buffer: U128,
+    /// Built with `mock-captcha`: purchases skip OutLayer and always verify
+    pub mock_captcha: bool,
+}
+
+/// In-flight purchase, as returned by `get_pending_purchase`
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingInfo {
+    #[schemars(with = "String")]
+    pub buyer: AccountId,
+    /// Receives any refund (the buyer unless a sponsor was set)
+    #[schemars(with = "String")]
+    pub refund_to: AccountId,
+    /// Purchase amount in yoctoNEAR
+    #[schemars(with = "String")]
+    pub amount: U128,
+    /// Tokens reserved for the purchase
+    #[schemars(with = "String")]
+    pub tokens: U128,
+    /// Time since `buy_tokens`
+    #[schemars(with = "String")]
+    pub elapsed_ns: U64,
+}
+
+/// Who pays for OutLayer execution, as returned by `get_execution_accounting`
+///
+/// OutLayer keeps the execution cost out of the deposit attached to
+/// `request_execution` and refunds the unused part directly to the payer
+/// (`refund_recipient`). Only the execution fee is attached; the purchase
+/// amount stays in this contract. That refund never passes through this contract's
+/// callback, so the totals below are what was attached, not what was spent.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ExecutionAccounting {
+    pub self_pays_execution: bool,
+    /// Receives OutLayer's refund of unused execution deposit: "buyer" (each
+    /// purchase's `payer_account_id`) or "contract"
+    pub refund_recipient: String,
+    /// Execution deposit attached per purchase, in yoctoNEAR
+    #[schemars(with = "String")]
+    pub execution_reserve: U128,
+    /// Execution fees taken from buyer deposits so far, in yoctoNEAR
+    #[schemars(with = "String")]
+    pub buyer_execution_fees: U128,
+    /// Execution reserves paid from the contract balance so far, in yoctoNEAR
+    #[schemars(with = "String")]
+    pub contract_execution_fees: U128,
+    /// Failed purchases also get their execution fee back from the contract balance
+    pub refund_execution_fee: bool,
+    /// Execution fees topped up on refunds so far, in yoctoNEAR
+    #[schemars(with = "String")]
+    pub refunded_execution_fees: U128,
+}
+
+/// Supply breakdown, as returned by `get_sale_stats`
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleStats {
+    #[schemars(with = "String")]
+    pub tokens_sold: U128,
+    #[schemars(with = "String")]
+    pub total_supply: U128,
+    /// Tokens held for purchases awaiting verification
+    #[schemars(with = "String")]
+    pub reserved: U128,
+    /// Tokens neither sold nor reserved
+    #[schemars(with = "String")]
+    pub remaining: U128,
+}
+
+/// Sale overview, as returned by `get_metrics`
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleMetrics {
+    #[schemars(with = "String")]
+    pub tokens_sold: U128,
+    #[schemars(with = "String")]
+    pub total_supply: U128,
+    /// Tokens neither sold nor reserved by pending purchases
+    #[schemars(with = "String")]
+    pub remaining: U128,
+    /// Accounts with at least one verified purchase
+    pub buyer_count: u64,
+    /// yoctoNEAR paid for verified purchases, excluding refunds
+    #[schemars(with = "String")]
+    pub near_raised: U128,
+    /// Share of the supply sold, in basis points
+    pub progress_bps: u16,
+    pub paused: bool,
+    pub sale_active: bool,
+}
+
+/// What an accepted purchase buys, as worked out by `check_purchase`
+struct CheckedPurchase {
+    /// yoctoNEAR spent on tokens, including any part above `max_purchase`
+    purchase_amount: u128,
+    /// yoctoNEAR attached to OutLayer
+    execution_fee: u128,
+    tokens_amount: u128,
+    /// yoctoNEAR refunded on success (partial fill or above `max_purchase`)
+    unfilled_amount: u128,
+    bonus_tokens: u128,
+}
+
+/// Purchase awaiting the OutLayer callback
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct PendingPurchase {
+    pub buyer: AccountId,
+    /// Purchase amount in yoctoNEAR (refunded on failure)
+    pub amount: u128,
+    /// Execution fee in yoctoNEAR attached to OutLayer
+    pub execution_fee: u128,
+    /// Tokens the purchase will credit on success
+    pub tokens: u128,
+    /// Part of `amount` not covered by a partial fill, refunded on success
+    pub unfilled_amount: u128,
+    /// Block timestamp of `buy_tokens`
+    pub created_at_ns: u64,
+    /// Discount code redeemed by the purchase, freed again if it fails
+    pub discount_code: Option<String>,
+    /// Part of `tokens` granted by the discount code
+    pub bonus_tokens: u128,
+    /// Receives refunds: the buyer, or a sponsor that paid for the purchase
+    pub refund_to: AccountId,
 }
 
 #[near_bindgen]
@@ -60,9 +555,93 @@ pub struct CaptchaResponse {
 #[borsh(crate = "near_sdk::borsh")]
 pub struct TokenSaleContract {
     owner: AccountId,
+    /// Identifies this sale in events and to the launchpad
+    sale_id: String,
+    /// Decimals of the sold token; supply, sales and balances are counted in its smallest unit
+    token_decimals: u8,
     tokens_sold: u128,
+    /// Tokens held for purchases awaiting the OutLayer callback
+    reserved_tokens: u128,
     total_supply: u128,
     launchpad_url: String,
+    allowlist: IterableSet<AccountId>,
+    /// Only allowlisted accounts may buy (private round)
+    allowlist_enabled: bool,
+    /// Merkle root of further allowlisted accounts, proven at purchase time
+    allowlist_root: Option<[u8; 32]>,
+    /// Accounts that may never buy, even in an open sale
+    blacklist: IterableSet<AccountId>,
+    /// OutLayer secrets profile holding the launchpad `API_TOKEN` (None = no secrets)
+    secrets_profile: Option<String>,
+    /// Number of purchases where OutLayer execution returned no result
+    execution_failures: u64,
+    /// GitHub repository OutLayer builds the worker from
+    worker_repo: String,
+    /// Worker commit (branch, tag or hash) OutLayer builds and runs
+    worker_commit: String,
+    /// Target OutLayer builds the worker for
+    build_target: String,
+    /// `response_format` requested from OutLayer
+    response_format: String,
+    /// Worker re-poll interval while a challenge is pending (None = worker default)
+    poll_interval_ms: Option<u64>,
+    /// Purchases awaiting the OutLayer callback, keyed by session ID
+    pending: LookupMap<String, PendingPurchase>,
+    /// Creation time of each pending purchase, for finding stuck ones
+    pending_sessions: IterableMap<String, u64>,
+    /// Sessions already settled or refunded; their IDs can't be reused
+    retired_sessions: LookupSet<String>,
+    /// Time after which the owner may force-refund a pending purchase
+    pending_timeout_ns: u64,
+    /// Static gas for `request_execution`; unused gas is added on top
+    min_request_gas: u64,
+    /// Most recent settled purchases per account
+    history: LookupMap<AccountId, Vec<PurchaseRecord>>,
+    /// Sell the remaining supply instead of rejecting purchases that exceed it
+    allow_partial_fill: bool,
+    /// Minimum purchase in yoctoNEAR, excluding the execution reserve
+    min_purchase: u128,
+    /// Maximum purchase in yoctoNEAR; any excess is refunded (None = unlimited)
+    max_purchase: Option<u128>,
+    /// Hard cap on a purchase in yoctoNEAR; larger ones are rejected (None = unlimited)
+    max_near_per_tx: Option<u128>,
+    /// Tokens purchasable per UTC day (None = unlimited)
+    daily_cap: Option<u128>,
+    /// Contract whose `on_token_purchase` is called after each verified purchase
+    post_purchase_hook: Option<AccountId>,
+    /// Tokens sold or reserved in the day starting at `day_start_ns`
+    sold_today: u128,
+    day_start_ns: u64,
+    /// Accounts with at least one verified purchase
+    buyers: LookupSet<AccountId>,
+    buyer_count: u64,
+    /// Tokens credited per account by verified purchases, including bonuses,
+    /// in the token's smallest unit
+    purchased: LookupMap<AccountId, u128>,
+    /// Minimum time between an account's verified purchase and its next buy (0 = none)
+    purchase_cooldown_ns: u64,
+    /// When each account's last verified purchase settled
+    last_purchase_ns: LookupMap<AccountId, u64>,
+    /// yoctoNEAR paid for verified purchases, excluding refunds
+    near_raised: u128,
+    /// Promo codes and the token bonus they grant, in basis points
+    discount_codes: LookupMap<String, u16>,
+    /// Codes redeemed per account, by verified or pending purchases
+    used_discounts: LookupSet<(AccountId, String)>,
+    /// Consecutive failed CAPTCHAs per account, reset by a verified purchase
+    failure_counts: LookupMap<AccountId, u8>,
+    /// Pay OutLayer execution from the contract balance instead of the buyer's deposit
+    self_pays_execution: bool,
+    /// Execution fees taken from buyer deposits and attached to OutLayer
+    buyer_execution_fees: u128,
+    /// Execution reserves attached to OutLayer from the contract balance
+    contract_execution_fees: u128,
+    /// Top up refunds with the execution fee OutLayer kept
+    refund_execution_fee: bool,
+    /// Execution fees returned to buyers from the contract balance
+    refunded_execution_fees: u128,
+    /// yoctoNEAR kept above the storage stake when sending NEAR out
+    storage_buffer: u128,
 }
 
 impl Default for TokenSaleContract {
@@ -77,15 +656,79 @@ impl TokenSaleContract {
     ///
     /// # Arguments
     /// * `owner` - Contract owner account
-    /// * `total_supply` - Total number of tokens available for sale
+    /// * `total_supply` - Total number of tokens available for sale, in the token's smallest unit
     /// * `launchpad_url` - URL of the launchpad backend API
+    /// * `sale_id` - Identifies the sale in events and worker input (defaults to the contract account ID)
+    /// * `token_decimals` - Decimals of the sold token (default 0, max 24); every token
+    ///   amount the contract takes or reports is in its smallest unit
     #[init]
-    pub fn new(owner: AccountId, total_supply: U128, launchpad_url: String) -> Self {
+    pub fn new(
+        owner: AccountId,
+        total_supply: U128,
+        launchpad_url: String,
+        sale_id: Option<String>,
+        token_decimals: Option<u8>,
+    ) -> Self {
+        let sale_id = sale_id.unwrap_or_else(|| env::current_account_id().to_string());
+        assert!(
+            !sale_id.is_empty() && sale_id.len() <= MAX_SALE_ID_LEN,
+            "Sale ID must be 1 to {} bytes",
+            MAX_SALE_ID_LEN
+        );
+        let token_decimals = token_decimals.unwrap_or(0);
+        assert!(
+            token_decimals <= MAX_TOKEN_DECIMALS,
+            "Token decimals must be at most {}",
+            MAX_TOKEN_DECIMALS
+        );
         Self {
             owner,
+            sale_id,
+            token_decimals,
             tokens_sold: 0,
+            reserved_tokens: 0,
             total_supply: total_supply.0,
             launchpad_url,
+            allowlist: IterableSet::new(StorageKey::Allowlist),
+            allowlist_enabled: false,
+            allowlist_root: None,
+            blacklist: IterableSet::new(StorageKey::Blacklist),
+            secrets_profile: None,
+            execution_failures: 0,
+            worker_repo: DEFAULT_WORKER_REPO.to_string(),
+            worker_commit: DEFAULT_WORKER_COMMIT.to_string(),
+            build_target: DEFAULT_BUILD_TARGET.to_string(),
+            response_format: DEFAULT_RESPONSE_FORMAT.to_string(),
+            poll_interval_ms: None,
+            pending: LookupMap::new(StorageKey::Pending),
+            pending_sessions: IterableMap::new(StorageKey::PendingSessions),
+            retired_sessions: LookupSet::new(StorageKey::RetiredSessions),
+            pending_timeout_ns: DEFAULT_PENDING_TIMEOUT_NS,
+            min_request_gas: DEFAULT_MIN_REQUEST_GAS,
+            history: LookupMap::new(StorageKey::History),
+            allow_partial_fill: false,
+            min_purchase: DEFAULT_MIN_PURCHASE,
+            max_purchase: None,
+            max_near_per_tx: None,
+            daily_cap: None,
+            post_purchase_hook: None,
+            sold_today: 0,
+            day_start_ns: 0,
+            buyers: LookupSet::new(StorageKey::Buyers),
+            buyer_count: 0,
+            purchased: LookupMap::new(StorageKey::Purchased),
+            purchase_cooldown_ns: 0,
+            last_purchase_ns: LookupMap::new(StorageKey::LastPurchase),
+            near_raised: 0,
+            discount_codes: LookupMap::new(StorageKey::DiscountCodes),
+            used_discounts: LookupSet::new(StorageKey::UsedDiscounts),
+            failure_counts: LookupMap::new(StorageKey::FailureCounts),
+            self_pays_execution: false,
+            buyer_execution_fees: 0,
+            contract_execution_fees: 0,
+            refund_execution_fee: false,
+            refunded_execution_fees: 0,
+            storage_buffer: DEFAULT_STORAGE_BUFFER,
         }
     }
 
@@ -93,41 +736,118 @@ impl TokenSaleContract {
     ///
     /// # Arguments
     /// * `session_id` - User's browser session ID from launchpad website
+    /// * `answer` - Optional CAPTCHA answer collected by the frontend; the worker
+    ///   verifies it directly instead of waiting for the user to solve
+    /// * `memo` - Optional reference (e.g. an order ID) echoed in the `token_purchase`
+    ///   event for off-chain reconciliation, at most 256 bytes
+    /// * `code` - Optional discount code granting bonus tokens, once per account
+    /// * `proof` - Merkle proof (hex sibling hashes, leaf first) that the buyer is
+    ///   in the allowlist root; only needed while the allowlist is enabled and
+    ///   the buyer isn't in the on-chain allowlist
+    /// * `refund_to` - Account that receives any refund, e.g. the sponsor paying
+    ///   for someone else's purchase (defaults to the buyer); tokens always go
+    ///   to the buyer
+    /// * `client_ip` / `user_agent` - Optional client fingerprint from the launchpad
+    ///   front-end, forwarded to the launchpad for risk scoring (at most 64 and
+    ///   512 bytes; public on-chain like all arguments)
     ///
     /// # Payment
-    /// Attach at least 1 NEAR (minimum purchase)
-    /// Plus additional 0.1 NEAR for OutLayer execution
+    /// Attach at least the minimum purchase (0.1 NEAR by default)
+    /// Plus additional 0.01 NEAR for OutLayer execution, unless the contract
+    /// pays for execution (`self_pays_execution`)
     ///
     /// # Returns
     /// Promise that will resolve with success/failure message
     #[payable]
-    pub fn buy_tokens(&mut self, session_id: String) -> Promise {
+    #[allow(clippy::too_many_arguments)]
+    pub fn buy_tokens(
+        &mut self,
+        session_id: String,
+        answer: Option<String>,
+        memo: Option<String>,
+        code: Option<String>,
+        proof: Option<Vec<String>>,
+        refund_to: Option<AccountId>,
+        client_ip: Option<String>,
+        user_agent: Option<String>,
+    ) -> Promise {
         let buyer = env::predecessor_account_id();
         let total_attached = env::attached_deposit();
 
-        // Minimum: 0.1 NEAR for tokens + 0.01 NEAR for execution (unused amount will be refunded)
-        let min_total = MIN_PURCHASE + 10_000_000_000_000_000_000_000; // 0.11 NEAR
-        assert!(
-            total_attached.as_yoctonear() >= min_total,
-            "Attach at least 0.11 NEAR (0.1 NEAR minimum purchase + 0.01 NEAR for OutLayer execution)"
-        );
+        for (field, value, max_len) in [
+            ("Client IP", &client_ip, MAX_CLIENT_IP_LEN),
+            ("User agent", &user_agent, MAX_USER_AGENT_LEN),
+        ] {
+            if let Some(value) = value {
+                assert!(
+                    value.len() <= max_len,
+                    "{} is {} bytes, maximum is {} bytes",
+                    field,
+                    value.len(),
+                    max_len
+                );
+            }
+        }
 
-        // Calculate purchase amount (first NEAR goes to tokens, rest to execution)
-        let purchase_amount = if total_attached.as_yoctonear() >= MIN_PURCHASE * 2 {
-            total_attached.as_yoctonear() - 10_000_000_000_000_000_000_000 // Leave 0.01 for execution
-        } else {
-            MIN_PURCHASE
-        };
+        if let Some(memo) = &memo {
+            assert!(
+                memo.len() <= MAX_MEMO_LEN,
+                "Memo is {} bytes, maximum is {} bytes",
+                memo.len(),
+                MAX_MEMO_LEN
+            );
+        }
 
-        let tokens_amount = (purchase_amount / 1_000_000_000_000_000_000_000_000) * TOKENS_PER_NEAR;
+        // Checked before OutLayer is called, so rejected purchases pay no execution fee
+        let CheckedPurchase {
+            purchase_amount,
+            execution_fee,
+            tokens_amount,
+            unfilled_amount,
+            bonus_tokens,
+        } = self
+            .check_purchase(&buyer, total_attached.as_yoctonear(), code.as_deref(), proof.as_deref())
+            .unwrap_or_else(|reason| env::panic_str(&reason));
+        let reserved_amount = tokens_amount + bonus_tokens;
+
+        // Like the supply, the daily cap counts reserved tokens; failed purchases give them back
+        self.roll_daily_window();
+        self.sold_today += reserved_amount;
 
         assert!(
-            self.tokens_sold + tokens_amount <= self.total_supply,
-            "Not enough tokens available. Sold: {}, Requested: {}, Total: {}",
-            self.tokens_sold,
-            tokens_amount,
-            self.total_supply
+            !self.pending.contains_key(&session_id),
+            "Session {} already has a purchase being verified",
+            session_id
+        );
+        // A late callback for a cancelled purchase would otherwise settle this
+        // one, and the launchpad replays a settled session's CAPTCHA result
+        assert!(
+            !self.retired_sessions.contains(&session_id),
+            "Session {} was already used, start a new session",
+            session_id
+        );
+        self.pending.insert(
+            session_id.clone(),
+            PendingPurchase {
+                buyer: buyer.clone(),
+                amount: purchase_amount,
+                execution_fee,
+                tokens: reserved_amount,
+                unfilled_amount,
+                created_at_ns: env::block_timestamp(),
+                discount_code: code.clone(),
+                bonus_tokens,
+                refund_to: refund_to.unwrap_or_else(|| buyer.clone()),
+            },
         );
+        self.pending_sessions.insert(session_id.clone(), env::block_timestamp());
+        self.reserved_tokens = self
+            .reserved_tokens
+            .checked_add(reserved_amount)
+            .unwrap_or_else(|| env::panic_str("Reserved tokens overflow"));
+        if let Some(code) = code {
+            self.used_discounts.insert((buyer.clone(), code));
+        }
 
         log!(
             "User {} requested {} tokens (session: {}). Verifying CAPTCHA...",
@@ -136,43 +856,33 @@ impl TokenSaleContract {
             session_id
         );
 
-        // Hardcoded parameters for captcha-ark
-        let code_source = near_sdk::serde_json::json!({
-            "repo": "https://github.com/zavodil/captcha-ark",
-            "commit": "main",
-            "build_target": "wasm32-wasip2"
-        });
-
-        let resource_limits = near_sdk::serde_json::json!({
-            "max_instructions": 50000000000u64,
-            "max_memory_mb": 128u32,
-            "max_execution_seconds": 40u64
-        });
-
-        let input_data = near_sdk::serde_json::json!({
-            "session_id": session_id,
-            "buyer": buyer.to_string(),
-            "amount": purchase_amount.to_string(),
-            "launchpad_url": self.launchpad_url
-        });
+        let input_data = near_sdk::serde_json::to_string(&WorkerInput {
+            session_id: &session_id,
+            sale_id: &self.sale_id,
+            buyer: &buyer,
+            amount: U128(purchase_amount),
+            expected_tokens: U128(reserved_amount),
+            launchpad_url: &self.launchpad_url,
+            poll_interval_ms: self.poll_interval_ms,
+            answer: answer.as_deref(),
+            difficulty: self.captcha_difficulty(&buyer),
+            client_ip: client_ip.as_deref(),
+            user_agent: user_agent.as_deref(),
+        })
+        .unwrap_or_else(|_| env::panic_str("Failed to serialize worker input"));
 
-        // Call OutLayer using ext_contract
-        // Pass buyer as payer_account_id so refund goes to buyer, not this contract
-        ext_outlayer::ext(OUTLAYER_CONTRACT_ID.parse().unwrap())
-            .with_attached_deposit(total_attached)
-            .with_unused_gas_weight(1) // All unused gas goes to request_execution
-            .request_execution(
-                code_source,
-                resource_limits,
-                input_data.to_string(),
-                None,
-                "Json".to_string(),
-                Some(buyer.clone()), // Refund to buyer, not this contract
-            )
+        self.request_execution(&session_id, &buyer, input_data, execution_fee)
             .then(
                 ext_self::ext(env::current_account_id())
                     .with_static_gas(Gas::from_gas(CALLBACK_GAS))
-                    .on_captcha_verified(buyer, NearToken::from_yoctonear(purchase_amount)),
+                    .on_captcha_verified(
+                        session_id,
+                        buyer,
+                        NearToken::from_yoctonear(purchase_amount),
+                        U128(tokens_amount),
+                        NearToken::from_yoctonear(execution_fee),
+                        memo,
+                    ),
             )
     }
 
@@ -183,24 +893,104 @@ impl TokenSaleContract {
     /// - Ok(Some(CaptchaResponse{verified: false})) - CAPTCHA failed, refund buyer
     /// - Ok(None) - Execution failed (worker error, timeout, etc.), refund buyer
     /// - Err(_) - Promise system error (should never happen)
+    ///
+    /// `tokens` is the amount computed and reserved by `buy_tokens`; it is
+    /// credited as is rather than recomputed from `amount`.
+    ///
+    /// Failures refund only `amount` (the purchase part of the deposit).
+    /// `execution_fee` is not refunded here: it was attached to OutLayer,
+    /// which keeps the execution cost and returns any unused part directly
+    /// to the payer (see `get_execution_accounting`).
     #[private]
+    #[allow(clippy::too_many_arguments)]
     pub fn on_captcha_verified(
         &mut self,
+        session_id: String,
         buyer: AccountId,
         amount: NearToken,
-        #[callback_result] result: Result<Option<CaptchaResponse>, PromiseError>,
+        tokens: U128,
+        execution_fee: NearToken,
+        memo: Option<String>,
+        #[callback_result] result: Result<Option<near_sdk::serde_json::Value>, PromiseError>,
     ) -> String {
-        match result {
-            // Success case: We received Some(CaptchaResponse)
-            Ok(Some(response)) if response.verified => {
-                log!("✅ CAPTCHA verified for {}: {:?}", buyer, response.verified);
+        // The owner may already have force-refunded this purchase
+        let Some(pending) = self.pending.remove(&session_id) else {
+            log!(
+                "Ignoring callback for session {}: purchase was already settled",
+                session_id
+            );
+            return format!("Session {} was already settled. No action taken.", session_id);
+        };
+        self.pending_sessions.remove(&session_id);
+        self.retired_sessions.insert(session_id.clone());
+        let result = parse_captcha_result(result);
+
+        // Release the reservation; a verified purchase turns it into sold tokens below
+        self.release_reserved(pending.tokens);
+
+        if let Ok(Some(response)) = &result {
+            if response.solve_latency_ms.is_some() || response.attempts.is_some() {
+                log!(
+                    "Verification metrics for {}: latency {:?} ms, attempts {:?}",
+                    buyer,
+                    response.solve_latency_ms,
+                    response.attempts
+                );
+            }
+            if let Some(worker_version) = &response.worker_version {
+                log!("Verified by worker {}", worker_version);
+            }
+        }
+
+        let (receipt, receipt_timestamp) = match &result {
+            Ok(Some(response)) => (response.receipt.clone(), response.receipt_timestamp),
+            _ => (None, None),
+        };
+
+        let outcome = interpret_captcha(&result);
+        self.track_failures(&buyer, &outcome);
+        let (tokens_credited, refunded) = if outcome == CaptchaOutcome::Verified {
+            (tokens.0 + pending.bonus_tokens, 0)
+        } else {
+            self.release_daily(pending.tokens, pending.created_at_ns);
+            self.release_discount(&pending);
+            (0, self.refund_amount(amount.as_yoctonear(), execution_fee.as_yoctonear()))
+        };
+        self.record_purchase(
+            &buyer,
+            PurchaseRecord {
+                session_id: session_id.clone(),
+                tokens: U128(tokens_credited),
+                near_amount: U128(amount.as_yoctonear()),
+                timestamp_ns: U64(env::block_timestamp()),
+                outcome: outcome.label().to_string(),
+                execution_fee: U128(execution_fee.as_yoctonear()),
+                refunded: U128(refunded),
+            },
+        );
+
+        match outcome {
+            // Success case: We received Some(CaptchaResponse) with verified = true
+            CaptchaOutcome::Verified => {
+                log!("✅ CAPTCHA verified for {}", buyer);
 
-                // Calculate tokens to issue
-                let tokens_amount =
-                    (amount.as_yoctonear() / 1_000_000_000_000_000_000_000_000) * TOKENS_PER_NEAR;
+                // Issue the tokens computed at purchase time, plus any discount bonus
+                let tokens_amount = tokens_credited;
 
                 // Update state
-                self.tokens_sold += tokens_amount;
+                self.tokens_sold = self
+                    .tokens_sold
+                    .checked_add(tokens_amount)
+                    .unwrap_or_else(|| env::panic_str("Tokens sold overflow"));
+                self.near_raised = self
+                    .near_raised
+                    .saturating_add(amount.as_yoctonear() - pending.unfilled_amount);
+                if self.buyers.insert(buyer.clone()) {
+                    self.buyer_count += 1;
+                }
+                let purchased = self.purchased.entry(buyer.clone()).or_insert(0);
+                *purchased = purchased.saturating_add(tokens_amount);
+                self.last_purchase_ns.insert(buyer.clone(), env::block_timestamp());
 
                 log!(
                     "Token sale completed: {} bought {} tokens for {} NEAR",
@@ -208,70 +998,149 @@ impl TokenSaleContract {
                     tokens_amount,
                     amount.as_near()
                 );
+                self.emit_event(
+                    "token_purchase",
+                    near_sdk::serde_json::json!({
+                        "session_id": session_id,
+                        "buyer": buyer,
+                        "tokens": U128(tokens_amount),
+                        "bonus_tokens": U128(pending.bonus_tokens),
+                        "discount_code": pending.discount_code,
+                        "near_amount": U128(amount.as_yoctonear() - pending.unfilled_amount),
+                        "memo": memo,
+                        "receipt": receipt,
+                        "receipt_timestamp": receipt_timestamp,
+                    }),
+                );
+
+                // Fire-and-forget: a failing hook doesn't affect the purchase
+                if let Some(hook) = &self.post_purchase_hook {
+                    let _ = ext_hook::ext(hook.clone())
+                        .with_static_gas(Gas::from_gas(HOOK_GAS))
+                        .with_unused_gas_weight(0)
+                        .on_token_purchase(buyer.clone(), U128(tokens_amount));
+                }
+
+                // Partial fill: return the part of the purchase the remaining supply
+                // or the purchase cap didn't cover
+                if pending.unfilled_amount > 0 {
+                    log!(
+                        "Partial fill: refunding {} yoctoNEAR to {}",
+                        pending.unfilled_amount,
+                        pending.refund_to
+                    );
+                    self.assert_storage_solvent(pending.unfilled_amount);
+                    let _ = Promise::new(pending.refund_to.clone())
+                        .transfer(NearToken::from_yoctonear(pending.unfilled_amount));
+
+                    return format!(
+                        "Success! You bought {} tokens (partial fill). Refunded {} yoctoNEAR for the unfilled part. Session: {}",
+                        tokens_amount,
+                        pending.unfilled_amount,
+                        session_id
+                    );
+                }
 
                 format!(
                     "Success! You bought {} tokens for {} NEAR. Session: {}",
                     tokens_amount,
                     amount.as_near(),
-                    response.session_id
+                    session_id
                 )
             }
 
             // CAPTCHA failed case
-            Ok(Some(response)) => {
-                let error_type = response.error_type.as_deref().unwrap_or("unknown");
+            CaptchaOutcome::Failed(error_type) => {
+                let error = match result {
+                    Ok(Some(response)) => response.error,
+                    _ => None,
+                };
 
                 log!(
                     "❌ CAPTCHA verification failed for {} (type: {}): {:?}",
                     buyer,
-                    error_type,
-                    response.error
+                    error_type.as_str(),
+                    error
                 );
 
                 // Refund the buyer
-                Promise::new(buyer.clone()).transfer(amount);
+                self.refund(&pending.refund_to, amount, execution_fee);
 
                 // Different messages for different error types
                 match error_type {
-                    "wrong_answer" => format!(
+                    CaptchaErrorType::WrongAnswer => format!(
                         "❌ CAPTCHA failed: Wrong answer. Transaction cancelled. Refunded {} NEAR.",
                         amount.as_near()
                     ),
-                    "timeout" => format!(
+                    CaptchaErrorType::Timeout => format!(
                         "⏱ CAPTCHA timeout: You didn't complete CAPTCHA in time. Transaction cancelled. Refunded {} NEAR.",
                         amount.as_near()
                     ),
-                    "network_error" => format!(
+                    CaptchaErrorType::RateLimited => format!(
+                        "🚦 Launchpad is rate limiting verifications. Transaction cancelled. Refunded {} NEAR. Please try again shortly.",
+                        amount.as_near()
+                    ),
+                    CaptchaErrorType::NetworkError => format!(
                         "🌐 Network error during CAPTCHA verification. Transaction cancelled. Refunded {} NEAR.",
                         amount.as_near()
                     ),
-                    _ => format!(
+                    CaptchaErrorType::AuthError => format!(
+                        "🔒 Launchpad rejected the verifier's credentials. Transaction cancelled. Refunded {} NEAR.",
+                        amount.as_near()
+                    ),
+                    CaptchaErrorType::SignatureInvalid => format!(
+                        "🛡 Launchpad response failed its signature check. Transaction cancelled. Refunded {} NEAR.",
+                        amount.as_near()
+                    ),
+                    CaptchaErrorType::BindingMismatch => format!(
+                        "🔗 CAPTCHA result didn't match this purchase. Transaction cancelled. Refunded {} NEAR.",
+                        amount.as_near()
+                    ),
+                    CaptchaErrorType::RedirectError => format!(
+                        "↪ Launchpad sent the verifier to an address it won't follow. Transaction cancelled. Refunded {} NEAR.",
+                        amount.as_near()
+                    ),
+                    CaptchaErrorType::Expired => format!(
+                        "⌛ CAPTCHA challenge expired before it was solved. Please try again. Refunded {} NEAR.",
+                        amount.as_near()
+                    ),
+                    CaptchaErrorType::SystemError | CaptchaErrorType::Unknown => format!(
                         "❌ CAPTCHA verification failed. Transaction cancelled. Refunded {} NEAR. Error: {:?}",
                         amount.as_near(),
-                        response.error.unwrap_or_else(|| "Unknown error".to_string())
+                        error.unwrap_or_else(|| "Unknown error".to_string())
                     ),
                 }
             }
 
             // Execution failed (OutLayer returned None)
-            Ok(None) => {
+            CaptchaOutcome::ExecutionFailed => {
                 log!("❌ OutLayer execution failed for {} - received None", buyer);
 
+                self.execution_failures += 1;
+                self.emit_event(
+                    "execution_failed",
+                    near_sdk::serde_json::json!({
+                        "buyer": buyer,
+                        "refunded": U128(refunded),
+                    }),
+                );
+
                 // Refund the buyer
-                Promise::new(buyer.clone()).transfer(amount);
+                self.refund(&pending.refund_to, amount, execution_fee);
 
                 format!(
-                    "Verification error (execution failed). Refunded {} NEAR.",
+                    "Verification error (execution failed). Refunded {} NEAR. This is usually temporary, please try again.",
                     amount.as_near()
                 )
             }
 
             // Promise error (should never happen)
-            Err(promise_error) => {
+            CaptchaOutcome::SystemError => {
+                let promise_error = result.unwrap_err();
                 log!("❌ Promise system error for {}: {:?}", buyer, promise_error);
 
                 // Refund the buyer
-                Promise::new(buyer.clone()).transfer(amount);
+                self.refund(&pending.refund_to, amount, execution_fee);
 
                 format!(
                     "System error. Refunded {} NEAR. Error: {:?}",
@@ -284,16 +1153,300 @@ impl TokenSaleContract {
 
     // ========== View methods ==========
 
-    /// Get sale statistics
+    /// Get sale statistics as `(tokens_sold, total_supply)`
+    ///
+    /// Kept for existing clients; prefer `get_sale_stats`.
     pub fn get_stats(&self) -> (U128, U128) {
         (U128(self.tokens_sold), U128(self.total_supply))
     }
 
-    /// Get token price
+    /// Get sale statistics with named fields
+    pub fn get_sale_stats(&self) -> SaleStats {
+        SaleStats {
+            tokens_sold: U128(self.tokens_sold),
+            total_supply: U128(self.total_supply),
+            reserved: U128(self.reserved_tokens),
+            remaining: U128(self.available_tokens()),
+        }
+    }
+
+    /// Get tokens still available to buy (not sold or reserved)
+    pub fn get_remaining_supply(&self) -> U128 {
+        U128(self.available_tokens())
+    }
+
+    /// Get all sale metrics in one call
+    ///
+    /// There is no pause switch or sale window yet, so `paused` is always false
+    /// and `sale_active` is always true.
+    pub fn get_metrics(&self) -> SaleMetrics {
+        let paused = false;
+        SaleMetrics {
+            tokens_sold: U128(self.tokens_sold),
+            total_supply: U128(self.total_supply),
+            remaining: U128(self.available_tokens()),
+            buyer_count: self.buyer_count,
+            near_raised: U128(self.near_raised),
+            progress_bps: basis_points(self.tokens_sold, self.total_supply),
+            paused,
+            sale_active: !paused,
+        }
+    }
+
+    /// Check whether a purchase for this session is still being verified
+    pub fn is_session_active(&self, session_id: String) -> bool {
+        self.pending.contains_key(&session_id)
+    }
+
+    /// Get a purchase that is still being verified, or `None` once it has settled
+    ///
+    /// If `elapsed_ns` passes the pending timeout, the owner can `force_refund` it.
+    pub fn get_pending_purchase(&self, session_id: String) -> Option<PendingInfo> {
+        self.pending.get(&session_id).map(|pending| PendingInfo {
+            buyer: pending.buyer.clone(),
+            refund_to: pending.refund_to.clone(),
+            amount: U128(pending.amount),
+            tokens: U128(pending.tokens),
+            elapsed_ns: U64(env::block_timestamp().saturating_sub(pending.created_at_ns)),
+        })
+    }
+
+    /// Get the longest-pending purchases as `(session_id, created_at_ns)`, oldest first
+    ///
+    /// Sessions created in the same block are ordered by session ID. Returns at
+    /// most `limit` entries (capped at 100). Every pending session is read and
+    /// sorted on each call, so gas grows as O(n log n) with the number of
+    /// purchases awaiting a callback; that stays small unless callbacks stall.
+    pub fn get_oldest_pending(&self, limit: u64) -> Vec<(String, U64)> {
+        let mut sessions: Vec<(u64, &String)> = self
+            .pending_sessions
+            .iter()
+            .map(|(session_id, created_at_ns)| (*created_at_ns, session_id))
+            .collect();
+        sessions.sort_unstable();
+        sessions
+            .into_iter()
+            .take(limit.min(MAX_PAGE_LIMIT) as usize)
+            .map(|(created_at_ns, session_id)| (session_id.clone(), U64(created_at_ns)))
+            .collect()
+    }
+
+    /// Get an account's settled purchases, oldest first, paginated
+    ///
+    /// Only the latest 50 purchases per account are kept. Returns at most
+    /// `limit` records (capped at 100); empty if `from` is past the end.
+    pub fn get_history(&self, account: AccountId, from: u64, limit: u64) -> Vec<PurchaseRecord> {
+        self.history
+            .get(&account)
+            .map(|records| {
+                records
+                    .iter()
+                    .skip(from as usize)
+                    .take(limit.min(MAX_PAGE_LIMIT) as usize)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get the total tokens an account received from verified purchases
+    pub fn get_purchased(&self, account_id: AccountId) -> U128 {
+        U128(self.purchased.get(&account_id).copied().unwrap_or(0))
+    }
+
+    /// Get purchase totals for several accounts, in request order
+    ///
+    /// Unknown accounts report 0. Accepts at most 50 accounts per call.
+    pub fn get_purchased_batch(&self, account_ids: Vec<AccountId>) -> Vec<U128> {
+        assert!(
+            account_ids.len() <= MAX_BATCH_SIZE,
+            "At most {} accounts per batch",
+            MAX_BATCH_SIZE
+        );
+        account_ids
+            .into_iter()
+            .map(|account_id| self.get_purchased(account_id))
+            .collect()
+    }
+
+    /// Get number of purchases where OutLayer execution failed
+    pub fn get_execution_failures(&self) -> u64 {
+        self.execution_failures
+    }
+
+    /// Get token price, in whole tokens (not the smallest unit)
     pub fn get_price(&self) -> String {
         format!("{} tokens per 1 NEAR", TOKENS_PER_NEAR)
     }
 
+    /// Get the tokens per NEAR a purchase would yield right now, including bonuses
+    ///
+    /// Like `get_price`, this is in whole tokens. Discount codes are the only
+    /// bonus and need a code, so this matches `get_price` until a bonus that
+    /// applies without one exists; use `get_discounted_price` for a code.
+    pub fn get_effective_price(&self, account_id: Option<AccountId>) -> String {
+        self.get_discounted_price(account_id, None)
+    }
+
+    /// Get the tokens per NEAR a purchase with discount `code` would yield right now
+    ///
+    /// Like `get_price`, this is in whole tokens. `code` counts if it exists
+    /// and, when `account_id` is given, that account hasn't used it yet. The
+    /// bonus is capped by the remaining supply at purchase time, which this ignores.
+    pub fn get_discounted_price(&self, account_id: Option<AccountId>, code: Option<String>) -> String {
+        let bonus_bps = code
+            .filter(|code| {
+                account_id
+                    .as_ref()
+                    .is_none_or(|account_id| !self.used_discounts.contains(&(account_id.clone(), code.clone())))
+            })
+            .and_then(|code| self.get_discount_bps(code))
+            .unwrap_or(0);
+        let tokens_per_near = format_decimal(TOKENS_PER_NEAR * (10_000 + bonus_bps as u128), 4);
+        format!("{} tokens per 1 NEAR", tokens_per_near)
+    }
+
+    /// Preview what an attached deposit would buy
+    ///
+    /// Returns `(tokens, execution_fee_reserved)` using the same math as
+    /// `buy_tokens`: everything except the execution reserve buys tokens. With
+    /// partial fills enabled,
+    /// `tokens` is capped to the remaining supply. Returns `(0, 0)` if the
+    /// deposit is below the minimum or can't be filled.
+    pub fn quote(&self, attached: U128) -> (U128, U128) {
+        self.calculate_purchase(attached.0)
+            .and_then(|(purchase_amount, tokens_amount)| {
+                self.fill_purchase(purchase_amount, tokens_amount)
+                    .filter(|(tokens, _)| *tokens <= self.daily_remaining())
+                    .map(|(tokens, _)| (U128(tokens), U128(attached.0 - purchase_amount)))
+            })
+            .unwrap_or((U128(0), U128(0)))
+    }
+
+    /// Check whether a purchase would currently be accepted
+    ///
+    /// Runs the same checks as `buy_tokens` up to the OutLayer call: allowlist,
+    /// minimum deposit, supply, discount code and daily cap. Returns `None` if
+    /// the purchase would go ahead, or the reason `buy_tokens` would fail. The
+    /// CAPTCHA itself can of course still fail.
+    pub fn can_buy(
+        &self,
+        account_id: AccountId,
+        attached: U128,
+        code: Option<String>,
+        proof: Option<Vec<String>>,
+    ) -> Option<String> {
+        self.check_purchase(&account_id, attached.0, code.as_deref(), proof.as_deref())
+            .err()
+    }
+
+    /// Get the minimum deposit (yoctoNEAR) that buys at least `tokens`
+    ///
+    /// Includes the OutLayer execution reserve, and is never below the minimum
+    /// deposit. Supply, daily cap and maximum purchase are not checked; use
+    /// `quote` to see what a deposit actually buys.
+    pub fn get_required_deposit(&self, tokens: U128) -> U128 {
+        let cost = token_cost(tokens.0, self.token_decimals);
+        U128(cost.max(self.min_purchase) + self.buyer_execution_reserve())
+    }
+
+    /// Get the smallest deposit `buy_tokens` accepts, in yoctoNEAR
+    ///
+    /// The minimum purchase plus the execution reserve, if buyers pay for
+    /// execution. Front-ends should read this rather than hardcode 0.11 NEAR.
+    pub fn get_min_deposit(&self) -> U128 {
+        U128(self.min_purchase + self.buyer_execution_reserve())
+    }
+
+    /// Get who pays for OutLayer execution and the execution fees attached so far
+    pub fn get_execution_accounting(&self) -> ExecutionAccounting {
+        ExecutionAccounting {
+            self_pays_execution: self.self_pays_execution,
+            refund_recipient: if self.self_pays_execution { "contract" } else { "buyer" }.to_string(),
+            execution_reserve: U128(EXECUTION_RESERVE),
+            buyer_execution_fees: U128(self.buyer_execution_fees),
+            contract_execution_fees: U128(self.contract_execution_fees),
+            refund_execution_fee: self.refund_execution_fee,
+            refunded_execution_fees: U128(self.refunded_execution_fees),
+        }
+    }
+
+    /// Get the token bonus of a discount code in basis points, `None` if unknown
+    pub fn get_discount_bps(&self, code: String) -> Option<u16> {
+        self.discount_codes.get(&code).copied()
+    }
+
+    /// Get the extra CAPTCHA difficulty the account's next purchase will request
+    ///
+    /// 0 is the launchpad's normal difficulty; each consecutive wrong answer or
+    /// timeout adds one, up to `MAX_CAPTCHA_DIFFICULTY`.
+    pub fn get_captcha_difficulty(&self, account_id: AccountId) -> u8 {
+        self.captcha_difficulty(&account_id).unwrap_or(0)
+    }
+
+    /// Get the version of this contract build
+    pub fn get_contract_version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
+    /// Get the ID this sale is tagged with in events and worker input
+    pub fn get_sale_id(&self) -> String {
+        self.sale_id.clone()
+    }
+
+    /// Get the `code_source` sent to OutLayer with every purchase, so the
+    /// pinned worker commit and build target can be audited on-chain
+    pub fn get_code_source(&self) -> near_sdk::serde_json::Value {
+        self.code_source()
+    }
+
+    /// Get sale parameters
+    pub fn get_config(&self) -> SaleConfig {
+        SaleConfig {
+            min_purchase: U128(self.min_purchase),
+            max_purchase: self.max_purchase.map(U128),
+            max_near_per_tx: self.max_near_per_tx.map(U128),
+            execution_reserve: U128(self.buyer_execution_reserve()),
+            tokens_per_near: TOKENS_PER_NEAR as u64,
+            token_decimals: self.token_decimals,
+            allow_partial_fill: self.allow_partial_fill,
+            allowlist_enabled: self.allowlist_enabled,
+            worker_repo: self.worker_repo.clone(),
+            worker_commit: self.worker_commit.clone(),
+            build_target: self.build_target.clone(),
+            response_format: self.response_format.clone(),
+            pending_timeout_ns: U64(self.pending_timeout_ns),
+            min_request_gas: U64(self.min_request_gas),
+            daily_cap: self.daily_cap.map(U128),
+            purchase_cooldown_ns: U64(self.purchase_cooldown_ns),
+            post_purchase_hook: self.post_purchase_hook.clone(),
+            storage_buffer: U128(self.storage_buffer),
+            mock_captcha: cfg!(feature = "mock-captcha"),
+        }
+    }
+
+    /// Get tokens still purchasable today
+    ///
+    /// The rest of the daily cap, bounded by the remaining supply; just the
+    /// remaining supply if there is no daily cap.
+    pub fn get_daily_remaining(&self) -> U128 {
+        U128(self.daily_remaining().min(self.available_tokens()))
+    }
+
+    /// Get contract version and pinned worker commit
+    ///
+    /// The version carries the contract's git commit as build metadata
+    /// (e.g. "0.1.0+1a2b3c4d5e6f") when it was available at build time.
+    pub fn get_version(&self) -> (String, String) {
+        let commit = env!("CONTRACT_GIT_COMMIT");
+        let version = if commit.is_empty() {
+            env!("CARGO_PKG_VERSION").to_string()
+        } else {
+            format!("{}+{}", env!("CARGO_PKG_VERSION"), commit)
+        };
+        (version, self.worker_commit.clone())
+    }
+
     /// Get launchpad URL
     pub fn get_launchpad_url(&self) -> String {
         self.launchpad_url.clone()
@@ -304,9 +1457,3182 @@ impl TokenSaleContract {
         self.owner.clone()
     }
 
-    pub fn set_launchpad_url(&mut self, url: String) {
-        // assert_eq!(self.owner, env::predecessor_account_id());
-        self.launchpad_url = url;
+    /// Get allowlisted accounts, paginated
+    ///
+    /// Returns at most `limit` accounts (capped at 100) starting at `from_index`.
+    /// Returns an empty list if `from_index` is past the end of the allowlist.
+    pub fn get_allowlist(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        self.allowlist
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.min(MAX_PAGE_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Check whether an account is in the on-chain allowlist
+    ///
+    /// Accounts covered only by the Merkle root are not listed here; they
+    /// prove membership when they buy.
+    pub fn is_allowlisted(&self, account: AccountId) -> bool {
+        self.allowlist.contains(&account)
     }
 
+    /// Get blacklisted accounts, paginated
+    ///
+    /// Returns at most `limit` accounts (capped at 100) starting at `from_index`.
+    pub fn get_blacklist(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        self.blacklist
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.min(MAX_PAGE_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Check whether an account is barred from buying
+    pub fn is_blacklisted(&self, account: AccountId) -> bool {
+        self.blacklist.contains(&account)
+    }
+
+    /// Alias of `is_allowlisted` under the whitelist naming
+    pub fn is_whitelisted(&self, account: AccountId) -> bool {
+        self.is_allowlisted(account)
+    }
+
+    /// Get the allowlist Merkle root as hex, if set
+    pub fn get_allowlist_root(&self) -> Option<String> {
+        self.allowlist_root.as_ref().map(hash_to_hex)
+    }
+
+    // ========== Owner methods ==========
+
+    /// Point the worker at another launchpad backend (owner only)
+    ///
+    /// The launchpad's answer decides every purchase, so only the owner may change it.
+    pub fn set_launchpad_url(&mut self, url: String) {
+        self.assert_owner();
+        self.launchpad_url = url;
+    }
+
+    /// Add accounts to the allowlist (owner only)
+    pub fn add_to_allowlist(&mut self, accounts: Vec<AccountId>) {
+        self.assert_owner();
+        for account in accounts {
+            self.allowlist.insert(account);
+        }
+    }
+
+    /// Set the OutLayer secrets profile passed as `secrets_ref` (owner only)
+    ///
+    /// The profile should contain `API_TOKEN` for launchpads that require
+    /// bearer authentication. Pass `None` to stop sending secrets.
+    pub fn set_secrets_profile(&mut self, profile: Option<String>) {
+        self.assert_owner();
+        self.secrets_profile = profile;
+    }
+
+    /// Add or update a discount code granting `bonus_bps` extra tokens (owner only)
+    pub fn add_discount_code(&mut self, code: String, bonus_bps: u16) {
+        self.assert_owner();
+        assert!(!code.is_empty(), "Discount code must not be empty");
+        assert!(
+            (1..=10_000).contains(&bonus_bps),
+            "Discount bonus must be between 1 and 10000 bps"
+        );
+        self.discount_codes.insert(code, bonus_bps);
+    }
+
+    /// Remove a discount code; pending purchases keep their bonus (owner only)
+    pub fn remove_discount_code(&mut self, code: String) {
+        self.assert_owner();
+        self.discount_codes.remove(&code);
+    }
+
+    /// Point OutLayer at a worker repository, e.g. a fork (owner only)
+    pub fn set_worker_repo(&mut self, repo: String) {
+        self.assert_owner();
+        let name = repo.strip_prefix("https://github.com/").unwrap_or_else(|| {
+            env::panic_str("Worker repo must start with https://github.com/")
+        });
+        assert!(!name.is_empty(), "Worker repo must name a repository");
+        self.worker_repo = repo;
+    }
+
+    /// Pin the worker commit OutLayer builds (owner only)
+    pub fn set_worker_commit(&mut self, commit: String) {
+        self.assert_owner();
+        assert!(!commit.is_empty(), "Worker commit must not be empty");
+        self.worker_commit = commit;
+    }
+
+    /// Set the target OutLayer builds the worker for (owner only)
+    pub fn set_build_target(&mut self, build_target: String) {
+        self.assert_owner();
+        assert!(
+            BUILD_TARGETS.contains(&build_target.as_str()),
+            "Unsupported build target {:?}, expected one of {:?}",
+            build_target,
+            BUILD_TARGETS
+        );
+        self.build_target = build_target;
+    }
+
+    /// Pay OutLayer execution from the contract balance (owner only)
+    ///
+    /// When enabled, buyers no longer attach the execution reserve: their whole
+    /// deposit is the purchase, the contract attaches `EXECUTION_RESERVE` itself
+    /// and OutLayer refunds the unused part to the contract.
+    pub fn set_self_pays_execution(&mut self, self_pays_execution: bool) {
+        self.assert_owner();
+        self.self_pays_execution = self_pays_execution;
+    }
+
+    /// Also refund the execution fee OutLayer kept when a purchase fails (owner only)
+    ///
+    /// The fee is paid from the contract balance, so failed attempts cost the
+    /// sale instead of the buyer. Off by default.
+    pub fn set_refund_execution_fee(&mut self, refund_execution_fee: bool) {
+        self.assert_owner();
+        self.refund_execution_fee = refund_execution_fee;
+    }
+
+    /// Set the yoctoNEAR kept above the storage stake when refunding (owner only)
+    ///
+    /// A refund that would leave less than the storage stake plus this buffer
+    /// panics instead, leaving the purchase pending until the contract is
+    /// topped up.
+    pub fn set_storage_buffer(&mut self, buffer: U128) {
+        self.assert_owner();
+        self.storage_buffer = buffer.0;
+    }
+
+    /// Set the `response_format` requested from OutLayer (owner only)
+    ///
+    /// Must be one of `RESPONSE_FORMATS`. `on_captcha_verified` expects the
+    /// worker's JSON output, so anything other than "Json" needs a matching callback.
+    pub fn set_response_format(&mut self, response_format: String) {
+        self.assert_owner();
+        assert!(
+            RESPONSE_FORMATS.contains(&response_format.as_str()),
+            "Unknown response format {:?}, expected one of {:?}",
+            response_format,
+            RESPONSE_FORMATS
+        );
+        self.response_format = response_format;
+    }
+
+    /// Set the worker's re-poll interval, or `None` for its default (owner only)
+    pub fn set_poll_interval_ms(&mut self, poll_interval_ms: Option<u64>) {
+        self.assert_owner();
+        if let Some(interval) = poll_interval_ms {
+            assert!(
+                (MIN_POLL_INTERVAL_MS..=MAX_POLL_INTERVAL_MS).contains(&interval),
+                "Poll interval must be between {} and {} ms",
+                MIN_POLL_INTERVAL_MS,
+                MAX_POLL_INTERVAL_MS
+            );
+        }
+        self.poll_interval_ms = poll_interval_ms;
+    }
+
+    /// Refund a purchase whose callback never arrived (owner only)
+    ///
+    /// Only allowed once the purchase has been pending for `pending_timeout_ns`.
+    /// A callback arriving afterwards is ignored.
+    pub fn force_refund(&mut self, session_id: String) {
+        self.assert_owner();
+        self.assert_timed_out(&session_id, "force refund");
+        self.cancel_pending(&session_id, "force_refunded");
+    }
+
+    /// Cancel your own purchase whose callback never arrived and get the purchase amount back
+    ///
+    /// Only the buyer may call this, once the purchase has been pending for
+    /// `pending_timeout_ns`. A callback arriving afterwards is ignored, and the
+    /// session ID can't be reused for a new purchase.
+    pub fn cancel_stuck_purchase(&mut self, session_id: String) {
+        let buyer = self.assert_timed_out(&session_id, "cancellation");
+        assert_eq!(
+            env::predecessor_account_id(),
+            buyer,
+            "Only the buyer can cancel this purchase"
+        );
+        self.cancel_pending(&session_id, "cancelled");
+    }
+
+    /// Refund up to `limit` purchases pending for at least `pending_timeout_ns`
+    ///
+    /// Anyone may call this, e.g. a keeper bot, to free reservations whose
+    /// callback never arrived. At most 20 purchases are swept per call; returns
+    /// how many were. A callback arriving afterwards is ignored.
+    pub fn sweep_expired(&mut self, limit: u64) -> u64 {
+        let now = env::block_timestamp();
+        let timeout_ns = self.pending_timeout_ns;
+        self.sweep_pending(limit, |created_at_ns| now.saturating_sub(created_at_ns) >= timeout_ns)
+    }
+
+    /// Refund purchases created before `before_ns` and free their reservations (owner only)
+    ///
+    /// The cutoff must be at least `pending_timeout_ns` in the past, so only
+    /// purchases `sweep_expired` could also refund are affected. At most 20
+    /// purchases are swept per call; call again until it returns 0.
+    pub fn sweep_expired_reservations(&mut self, before_ns: u64) -> u64 {
+        self.assert_owner();
+        let latest_cutoff = env::block_timestamp().saturating_sub(self.pending_timeout_ns);
+        assert!(
+            before_ns <= latest_cutoff,
+            "Cutoff must be at least {} ns in the past",
+            self.pending_timeout_ns
+        );
+        self.sweep_pending(MAX_SWEEP_LIMIT, |created_at_ns| created_at_ns < before_ns)
+    }
+
+    /// Remove up to `limit` orphaned session entries and return the bytes of storage freed
+    ///
+    /// Settling a purchase removes its `pending` and `pending_sessions`
+    /// entries together; this clears `pending_sessions` entries left without a
+    /// purchase (e.g. by older builds). Anyone may call it. At most 20 entries
+    /// are removed per call.
+    pub fn compact_state(&mut self, limit: u64) -> u64 {
+        let storage_before = env::storage_usage();
+        let orphaned: Vec<String> = self
+            .pending_sessions
+            .keys()
+            .filter(|session_id| !self.pending.contains_key(*session_id))
+            .take(limit.min(MAX_SWEEP_LIMIT) as usize)
+            .cloned()
+            .collect();
+
+        for session_id in &orphaned {
+            self.pending_sessions.remove(session_id);
+        }
+        self.pending_sessions.flush();
+        log!("Removed {} orphaned session entries", orphaned.len());
+        storage_before.saturating_sub(env::storage_usage())
+    }
+
+    /// Set the minimum purchase in yoctoNEAR, excluding the execution reserve (owner only)
+    pub fn set_min_purchase(&mut self, min_purchase: U128) {
+        self.assert_owner();
+        assert!(min_purchase.0 > 0, "Minimum purchase must be positive");
+        assert!(
+            self.max_purchase.is_none_or(|max| min_purchase.0 <= max),
+            "Minimum purchase must not exceed the maximum purchase"
+        );
+        self.min_purchase = min_purchase.0;
+    }
+
+    /// Cap a single purchase in yoctoNEAR, or `None` to remove the cap (owner only)
+    ///
+    /// Deposits above the cap still go through; the excess is refunded once the
+    /// purchase settles.
+    pub fn set_max_purchase_near(&mut self, max_purchase: Option<U128>) {
+        self.assert_owner();
+        if let Some(max) = max_purchase {
+            assert!(
+                max.0 >= self.min_purchase,
+                "Maximum purchase must be at least the minimum purchase ({} yoctoNEAR)",
+                self.min_purchase
+            );
+        }
+        self.max_purchase = max_purchase.map(|max| max.0);
+    }
+
+    /// Reject purchases above `max_near` yoctoNEAR, or `None` to remove the cap (owner only)
+    ///
+    /// Unlike `set_max_purchase_near`, nothing is trimmed: a larger purchase
+    /// fails before OutLayer is called, so it costs no execution fee.
+    pub fn set_max_near_per_tx(&mut self, max_near: Option<U128>) {
+        self.assert_owner();
+        if let Some(max) = max_near {
+            assert!(
+                max.0 >= self.min_purchase,
+                "Per-transaction cap must be at least the minimum purchase ({} yoctoNEAR)",
+                self.min_purchase
+            );
+        }
+        self.max_near_per_tx = max_near.map(|max| max.0);
+    }
+
+    /// Cap tokens purchasable per UTC day, or `None` to remove the cap (owner only)
+    pub fn set_daily_cap(&mut self, daily_cap: Option<U128>) {
+        self.assert_owner();
+        self.daily_cap = daily_cap.map(|cap| cap.0);
+    }
+
+    /// Set how long an account must wait after a verified purchase before buying again (owner only)
+    ///
+    /// Failed or refunded attempts don't restart the wait. Pass 0 to disable.
+    pub fn set_purchase_cooldown_ns(&mut self, cooldown_ns: u64) {
+        self.assert_owner();
+        self.purchase_cooldown_ns = cooldown_ns;
+    }
+
+    /// Set the contract notified of verified purchases (owner only)
+    ///
+    /// After each verified purchase it receives `on_token_purchase(buyer, tokens)`
+    /// with 3 TGas. The call is not awaited; pass `None` to stop calling it.
+    pub fn set_post_purchase_hook(&mut self, hook: Option<AccountId>) {
+        self.assert_owner();
+        self.post_purchase_hook = hook;
+    }
+
+    /// Set the Merkle root of allowlisted accounts as hex (owner only)
+    ///
+    /// Leaves are `sha256(account_id)`; each parent is the sha256 of its two
+    /// children, smaller first. Buyers in the tree pass their proof to
+    /// `buy_tokens`. Pass `None` to accept only the on-chain allowlist.
+    pub fn set_allowlist_root(&mut self, root: Option<String>) {
+        self.assert_owner();
+        self.allowlist_root = root.map(|root| {
+            hash_from_hex(&root).unwrap_or_else(|| env::panic_str("Allowlist root must be 32 bytes of hex"))
+        });
+    }
+
+    /// Restrict purchases to allowlisted accounts (owner only)
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.allowlist_enabled = enabled;
+    }
+
+    /// Sell the remaining supply when a purchase exceeds it, refunding the rest (owner only)
+    pub fn set_allow_partial_fill(&mut self, allow: bool) {
+        self.assert_owner();
+        self.allow_partial_fill = allow;
+    }
+
+    /// Set how long a purchase must be pending before `force_refund` (owner only)
+    pub fn set_pending_timeout_ns(&mut self, timeout_ns: u64) {
+        self.assert_owner();
+        self.pending_timeout_ns = timeout_ns;
+    }
+
+    /// Set the gas always reserved for OutLayer's `request_execution` call (owner only)
+    ///
+    /// The floor is attached as static gas and any gas left after scheduling
+    /// is added on top. The callback keeps its fixed 15 TGas, so the floor
+    /// plus the callback must fit in what buyers attach: raising it makes
+    /// low-gas purchases fail up front instead of starving the worker run.
+    pub fn set_min_request_gas(&mut self, gas: U64) {
+        self.assert_owner();
+        assert!(
+            gas.0 + CALLBACK_GAS < MAX_PREPAID_GAS,
+            "Request gas leaves no room for the callback"
+        );
+        self.min_request_gas = gas.0;
+    }
+
+    /// Add more tokens to the sale (owner only)
+    pub fn increase_supply(&mut self, additional: U128) {
+        self.assert_owner();
+        let old_total = self.total_supply;
+        self.total_supply = old_total
+            .checked_add(additional.0)
+            .unwrap_or_else(|| env::panic_str("Total supply overflow"));
+
+        self.emit_event(
+            "supply_increased",
+            near_sdk::serde_json::json!({
+                "old_total": U128(old_total),
+                "new_total": U128(self.total_supply),
+            }),
+        );
+    }
+
+    /// Remove unsold tokens from the sale (owner only)
+    ///
+    /// Supply can't drop below tokens already sold or reserved by pending purchases.
+    pub fn decrease_supply(&mut self, amount: U128) {
+        self.assert_owner();
+        let old_total = self.total_supply;
+        let committed = self.committed_tokens();
+        let new_total = old_total
+            .checked_sub(amount.0)
+            .filter(|new_total| *new_total >= committed)
+            .unwrap_or_else(|| {
+                env::panic_str(&format!(
+                    "Cannot decrease supply below committed tokens ({})",
+                    committed
+                ))
+            });
+        self.total_supply = new_total;
+
+        self.emit_event(
+            "supply_decreased",
+            near_sdk::serde_json::json!({
+                "old_total": U128(old_total),
+                "new_total": U128(new_total),
+            }),
+        );
+    }
+
+    /// Set the total supply for sale (owner only)
+    ///
+    /// Like `increase_supply`/`decrease_supply`, the total can't drop below
+    /// tokens already sold or reserved by pending purchases.
+    pub fn set_total_supply(&mut self, new_total: U128) {
+        self.assert_owner();
+        let committed = self.committed_tokens();
+        assert!(
+            new_total.0 >= committed,
+            "Cannot set supply below committed tokens ({})",
+            committed
+        );
+        let old_total = self.total_supply;
+        self.total_supply = new_total.0;
+
+        self.emit_event(
+            "supply_updated",
+            near_sdk::serde_json::json!({
+                "old_total": U128(old_total),
+                "new_total": new_total,
+            }),
+        );
+    }
+
+    /// Remove accounts from the allowlist (owner only)
+    pub fn remove_from_allowlist(&mut self, accounts: Vec<AccountId>) {
+        self.assert_owner();
+        for account in accounts {
+            self.allowlist.remove(&account);
+        }
+    }
+
+    /// Alias of `set_allowlist_enabled` under the whitelist naming (owner only)
+    pub fn set_whitelist_enabled(&mut self, enabled: bool) {
+        self.set_allowlist_enabled(enabled);
+    }
+
+    /// Alias of `add_to_allowlist` for one account (owner only)
+    pub fn whitelist_add(&mut self, account: AccountId) {
+        self.add_to_allowlist(vec![account]);
+    }
+
+    /// Alias of `remove_from_allowlist` for one account (owner only)
+    pub fn whitelist_remove(&mut self, account: AccountId) {
+        self.remove_from_allowlist(vec![account]);
+    }
+
+    /// Alias of `add_to_allowlist` (owner only)
+    pub fn whitelist_add_batch(&mut self, accounts: Vec<AccountId>) {
+        self.add_to_allowlist(accounts);
+    }
+
+    /// Alias of `remove_from_allowlist` (owner only)
+    pub fn whitelist_remove_batch(&mut self, accounts: Vec<AccountId>) {
+        self.remove_from_allowlist(accounts);
+    }
+
+    /// Bar accounts from buying, whether or not they are allowlisted (owner only)
+    ///
+    /// Purchases already pending still settle.
+    pub fn add_to_blacklist(&mut self, accounts: Vec<AccountId>) {
+        self.assert_owner();
+        for account in accounts {
+            self.blacklist.insert(account);
+        }
+    }
+
+    /// Let blacklisted accounts buy again (owner only)
+    pub fn remove_from_blacklist(&mut self, accounts: Vec<AccountId>) {
+        self.assert_owner();
+        for account in accounts {
+            self.blacklist.remove(&account);
+        }
+    }
+}
+
+/// Simulated OutLayer, compiled only into `mock-captcha` builds
+#[cfg(feature = "mock-captcha")]
+#[near_bindgen]
+impl TokenSaleContract {
+    /// Return a verified worker response, as OutLayer would after a solved CAPTCHA
+    #[private]
+    pub fn mock_request_execution(&self, session_id: String) -> Option<CaptchaResponse> {
+        Some(CaptchaResponse {
+            verified: true,
+            status: Some("verified".to_string()),
+            session_id,
+            error: None,
+            error_type: None,
+            solve_latency_ms: None,
+            attempts: None,
+            receipt: None,
+            receipt_timestamp: None,
+            worker_version: None,
+        })
+    }
+}
+
+impl TokenSaleContract {
+    /// Worker source sent to OutLayer, set by the owner
+    fn code_source(&self) -> near_sdk::serde_json::Value {
+        near_sdk::serde_json::json!({
+            "repo": self.worker_repo,
+            "commit": self.worker_commit,
+            "build_target": self.build_target
+        })
+    }
+
+    /// Schedule the worker run on OutLayer; the caller chains the verification callback
+    #[cfg(not(feature = "mock-captcha"))]
+    fn request_execution(
+        &mut self,
+        _session_id: &str,
+        buyer: &AccountId,
+        input_data: String,
+        execution_fee: u128,
+    ) -> Promise {
+        let code_source = self.code_source();
+
+        let resource_limits = near_sdk::serde_json::json!({
+            "max_instructions": 50000000000u64,
+            "max_memory_mb": 128u32,
+            "max_execution_seconds": 40u64
+        });
+
+        // Secrets are stored in OutLayer under the owner's account and injected
+        // into the worker as environment variables, never through input_data
+        let secrets_ref = self.secrets_profile.as_ref().map(|profile| {
+            near_sdk::serde_json::json!({
+                "profile": profile,
+                "account_id": self.owner
+            })
+        });
+
+        // Call OutLayer using ext_contract with only the execution fee; the
+        // purchase amount stays here to back refunds. The payer gets OutLayer's
+        // refund of unused execution deposit: the buyer, or this contract if it pays
+        let (execution_deposit, payer) = if self.self_pays_execution {
+            self.contract_execution_fees += EXECUTION_RESERVE;
+            (NearToken::from_yoctonear(EXECUTION_RESERVE), env::current_account_id())
+        } else {
+            self.buyer_execution_fees += execution_fee;
+            (NearToken::from_yoctonear(execution_fee), buyer.clone())
+        };
+        let remaining_gas = env::prepaid_gas().as_gas().saturating_sub(env::used_gas().as_gas());
+        if remaining_gas < self.min_request_gas + CALLBACK_GAS {
+            env::panic_str(&format!(
+                "Not enough gas: {} TGas needed for OutLayer and the callback, {} TGas left",
+                (self.min_request_gas + CALLBACK_GAS) / Gas::from_tgas(1).as_gas(),
+                remaining_gas / Gas::from_tgas(1).as_gas()
+            ));
+        }
+        ext_outlayer::ext(OUTLAYER_CONTRACT_ID.parse().unwrap())
+            .with_attached_deposit(execution_deposit)
+            .with_static_gas(Gas::from_gas(self.min_request_gas))
+            .with_unused_gas_weight(1) // All unused gas goes to request_execution
+            .request_execution(
+                code_source,
+                resource_limits,
+                input_data,
+                secrets_ref,
+                self.response_format.clone(),
+                Some(payer),
+            )
+    }
+
+    /// Testing builds only: skip OutLayer and report every CAPTCHA as solved
+    ///
+    /// The purchase deposit is handled as usual; the execution fee stays in
+    /// this contract since nothing is attached to OutLayer.
+    #[cfg(feature = "mock-captcha")]
+    fn request_execution(
+        &mut self,
+        session_id: &str,
+        _buyer: &AccountId,
+        _input_data: String,
+        _execution_fee: u128,
+    ) -> Promise {
+        log!("mock-captcha build: skipping OutLayer for session {}", session_id);
+        ext_mock_outlayer::ext(env::current_account_id())
+            .with_static_gas(Gas::from_gas(MOCK_REQUEST_GAS))
+            .mock_request_execution(session_id.to_string())
+    }
+
+    /// Split an attached deposit into `(purchase_amount, tokens_amount)`
+    ///
+    /// Shared by `buy_tokens` and `quote` so previews can't drift from real purchases.
+    /// Tokens are priced on at most `max_purchase`; see `fill_purchase` for the excess.
+    /// Returns `None` if the deposit doesn't cover the minimum purchase plus execution reserve.
+    fn calculate_purchase(&self, attached: u128) -> Option<(u128, u128)> {
+        let reserve = self.buyer_execution_reserve();
+        if attached < self.min_purchase + reserve {
+            return None;
+        }
+
+        // Everything except the reserve is the purchase, so an exact minimum
+        // deposit buys exactly the minimum purchase
+        let purchase_amount = attached - reserve;
+
+        let priced_amount = purchase_amount - self.over_max_purchase(purchase_amount);
+        Some((purchase_amount, calculate_tokens(priced_amount, self.token_decimals)))
+    }
+
+    /// Validate a purchase and work out what it buys, without changing state
+    ///
+    /// Shared by `buy_tokens` and `can_buy`; the error is the reason to reject.
+    fn check_purchase(
+        &self,
+        buyer: &AccountId,
+        attached: u128,
+        code: Option<&str>,
+        proof: Option<&[String]>,
+    ) -> Result<CheckedPurchase, String> {
+        if self.blacklist.contains(buyer) {
+            return Err("Account is blacklisted".to_string());
+        }
+
+        if self.allowlist_enabled && !self.allowlist.contains(buyer) && !self.allowlist_proof_valid(buyer, proof)? {
+            return Err(format!("{} is not on the allowlist", buyer));
+        }
+
+        if let Some(&last_purchase_ns) = self.last_purchase_ns.get(buyer) {
+            let ready_at_ns = last_purchase_ns.saturating_add(self.purchase_cooldown_ns);
+            if env::block_timestamp() < ready_at_ns {
+                return Err(format!(
+                    "{} must wait {} more seconds before buying again",
+                    buyer,
+                    (ready_at_ns - env::block_timestamp()).div_ceil(1_000_000_000)
+                ));
+            }
+        }
+
+        // Minimum: min_purchase for tokens + 0.01 NEAR for execution (unused amount will be refunded)
+        let (purchase_amount, tokens_amount) = self.calculate_purchase(attached).ok_or_else(|| {
+            format!(
+                "Attach at least {} NEAR ({} NEAR minimum purchase + {} NEAR for OutLayer execution)",
+                format_near(self.get_min_deposit().0),
+                format_near(self.min_purchase),
+                format_near(self.buyer_execution_reserve())
+            )
+        })?;
+        if let Some(max_near) = self.max_near_per_tx.filter(|&max| purchase_amount > max) {
+            return Err(format!(
+                "Purchase of {} NEAR exceeds the per-transaction cap of {} NEAR",
+                format_near(purchase_amount),
+                format_near(max_near)
+            ));
+        }
+        // Rejecting returns the deposit, so a purchase too small to price is refunded
+        if tokens_amount == 0 {
+            return Err(format!(
+                "{} NEAR buys no tokens at {} tokens per NEAR",
+                format_near(purchase_amount),
+                TOKENS_PER_NEAR
+            ));
+        }
+
+        // Reserved tokens count as taken, so concurrent purchases can't oversell
+        let (tokens_amount, unfilled_amount) =
+            self.fill_purchase(purchase_amount, tokens_amount).ok_or_else(|| {
+                format!(
+                    "Not enough tokens available. Sold: {}, Reserved: {}, Requested: {}, Total: {}",
+                    self.tokens_sold, self.reserved_tokens, tokens_amount, self.total_supply
+                )
+            })?;
+
+        // Discount bonus comes on top of the purchase, capped by the remaining supply
+        let bonus_tokens = match code {
+            Some(code) => {
+                let bonus_bps = self
+                    .get_discount_bps(code.to_string())
+                    .ok_or_else(|| format!("Unknown discount code {}", code))?;
+                if self.used_discounts.contains(&(buyer.clone(), code.to_string())) {
+                    return Err(format!("Discount code {} was already used by {}", code, buyer));
+                }
+                let bonus = tokens_amount
+                    .checked_mul(bonus_bps as u128)
+                    .unwrap_or_else(|| env::panic_str("Bonus tokens overflow"))
+                    / 10_000;
+                bonus.min(self.available_tokens() - tokens_amount)
+            }
+            None => 0,
+        };
+
+        let reserved_amount = tokens_amount + bonus_tokens;
+        if reserved_amount > self.daily_remaining() {
+            return Err(format!(
+                "Daily cap reached. Sold today: {}, Requested: {}, Daily cap: {}",
+                self.sold_in_current_day(),
+                reserved_amount,
+                self.daily_cap.unwrap_or_default()
+            ));
+        }
+
+        Ok(CheckedPurchase {
+            purchase_amount,
+            execution_fee: attached - purchase_amount,
+            tokens_amount,
+            unfilled_amount,
+            bonus_tokens,
+        })
+    }
+
+    /// Whether `proof` places `account` under the allowlist Merkle root
+    ///
+    /// The proof comes from the caller, so a malformed hash is a rejection
+    /// reason (for `can_buy` to report), not a panic.
+    fn allowlist_proof_valid(&self, account: &AccountId, proof: Option<&[String]>) -> Result<bool, String> {
+        let (Some(root), Some(proof)) = (&self.allowlist_root, proof) else {
+            return Ok(false);
+        };
+        let mut node = env::sha256_array(account.as_bytes());
+        for sibling in proof {
+            let sibling = hash_from_hex(sibling).ok_or_else(|| format!("Invalid Merkle proof hash {:?}", sibling))?;
+            node = merkle_parent(&node, &sibling);
+        }
+        Ok(&node == root)
+    }
+
+    /// Execution reserve taken from the buyer's deposit (none if the contract pays)
+    fn buyer_execution_reserve(&self) -> u128 {
+        if self.self_pays_execution {
+            0
+        } else {
+            EXECUTION_RESERVE
+        }
+    }
+
+    /// Part of a purchase above `max_purchase`, refunded on success
+    fn over_max_purchase(&self, purchase_amount: u128) -> u128 {
+        self.max_purchase
+            .map_or(0, |max| purchase_amount.saturating_sub(max))
+    }
+
+    /// Fit a purchase into the available supply, returning `(tokens, unfilled_amount)`
+    ///
+    /// Purchases that fit are filled completely, leaving only the part above
+    /// `max_purchase` unfilled. Otherwise, if partial fills are enabled, the
+    /// remaining supply is sold and the uncovered NEAR is returned as
+    /// `unfilled_amount`. Returns `None` if the purchase can't be filled.
+    fn fill_purchase(&self, purchase_amount: u128, tokens_amount: u128) -> Option<(u128, u128)> {
+        let available = self.available_tokens();
+        if tokens_amount <= available {
+            return Some((tokens_amount, self.over_max_purchase(purchase_amount)));
+        }
+        if !self.allow_partial_fill || available == 0 {
+            return None;
+        }
+
+        Some((available, purchase_amount - token_cost(available, self.token_decimals)))
+    }
+
+    /// Tokens sold or reserved by pending purchases
+    fn committed_tokens(&self) -> u128 {
+        self.tokens_sold
+            .checked_add(self.reserved_tokens)
+            .unwrap_or_else(|| env::panic_str("Committed tokens overflow"))
+    }
+
+    /// Tokens neither sold nor reserved by pending purchases
+    fn available_tokens(&self) -> u128 {
+        self.total_supply
+            .saturating_sub(self.tokens_sold)
+            .saturating_sub(self.reserved_tokens)
+    }
+
+    /// Tokens sold or reserved in the current UTC day
+    fn sold_in_current_day(&self) -> u128 {
+        if self.day_start_ns == day_start(env::block_timestamp()) {
+            self.sold_today
+        } else {
+            0
+        }
+    }
+
+    /// Tokens left under the daily cap (unbounded without a cap)
+    fn daily_remaining(&self) -> u128 {
+        self.daily_cap
+            .map_or(u128::MAX, |cap| cap.saturating_sub(self.sold_in_current_day()))
+    }
+
+    /// Reset the daily counter once a new UTC day has started
+    fn roll_daily_window(&mut self) {
+        let today = day_start(env::block_timestamp());
+        if self.day_start_ns != today {
+            self.day_start_ns = today;
+            self.sold_today = 0;
+        }
+    }
+
+    /// Escalate CAPTCHA difficulty after a failed challenge, reset it on success
+    ///
+    /// Only failures a bot would cause count; launchpad or OutLayer problems don't.
+    fn track_failures(&mut self, buyer: &AccountId, outcome: &CaptchaOutcome) {
+        match outcome {
+            CaptchaOutcome::Verified => {
+                self.failure_counts.remove(buyer);
+            }
+            CaptchaOutcome::Failed(CaptchaErrorType::WrongAnswer | CaptchaErrorType::Timeout) => {
+                let failures = self.failure_counts.get(buyer).copied().unwrap_or(0);
+                self.failure_counts
+                    .insert(buyer.clone(), failures.saturating_add(1).min(MAX_CAPTCHA_DIFFICULTY));
+            }
+            _ => {}
+        }
+    }
+
+    /// Difficulty passed to the worker, `None` for an account without recent failures
+    fn captcha_difficulty(&self, account_id: &AccountId) -> Option<u8> {
+        self.failure_counts
+            .get(account_id)
+            .map(|failures| (*failures).min(MAX_CAPTCHA_DIFFICULTY))
+    }
+
+    /// Check a purchase has been pending for `pending_timeout_ns` and return its buyer
+    fn assert_timed_out(&self, session_id: &str, action: &str) -> AccountId {
+        let pending = self
+            .pending
+            .get(session_id)
+            .unwrap_or_else(|| env::panic_str("No pending purchase for this session"));
+        let elapsed = env::block_timestamp().saturating_sub(pending.created_at_ns);
+        assert!(
+            elapsed >= self.pending_timeout_ns,
+            "Purchase has been pending for {} ns, {} allowed after {} ns",
+            elapsed,
+            action,
+            self.pending_timeout_ns
+        );
+        pending.buyer.clone()
+    }
+
+    /// Remove a pending purchase, release what it reserved and refund the buyer
+    ///
+    /// `outcome` names both the history outcome and the emitted event. The
+    /// session ID is retired, so a late callback can't settle a new purchase
+    /// that reused it.
+    fn cancel_pending(&mut self, session_id: &str, outcome: &str) {
+        let pending = self
+            .pending
+            .remove(session_id)
+            .unwrap_or_else(|| env::panic_str("No pending purchase for this session"));
+        self.pending_sessions.remove(session_id);
+        self.retired_sessions.insert(session_id.to_string());
+        self.release_reserved(pending.tokens);
+        self.release_daily(pending.tokens, pending.created_at_ns);
+        self.release_discount(&pending);
+        self.record_purchase(
+            &pending.buyer,
+            PurchaseRecord {
+                session_id: session_id.to_string(),
+                tokens: U128(0),
+                near_amount: U128(pending.amount),
+                timestamp_ns: U64(env::block_timestamp()),
+                outcome: outcome.to_string(),
+                execution_fee: U128(pending.execution_fee),
+                refunded: U128(self.refund_amount(pending.amount, pending.execution_fee)),
+            },
+        );
+        self.refund(
+            &pending.refund_to,
+            NearToken::from_yoctonear(pending.amount),
+            NearToken::from_yoctonear(pending.execution_fee),
+        );
+
+        self.emit_event(
+            outcome,
+            near_sdk::serde_json::json!({
+                "session_id": session_id,
+                "buyer": pending.buyer,
+                "refunded": U128(self.refund_amount(pending.amount, pending.execution_fee)),
+            }),
+        );
+    }
+
+    /// Refund up to `limit` (capped at `MAX_SWEEP_LIMIT`) pending purchases
+    /// whose creation time matches `expired`; returns how many were swept
+    fn sweep_pending(&mut self, limit: u64, expired: impl Fn(u64) -> bool) -> u64 {
+        let sessions: Vec<String> = self
+            .pending_sessions
+            .iter()
+            .filter(|(_, created_at_ns)| expired(**created_at_ns))
+            .map(|(session_id, _)| session_id.clone())
+            .take(limit.min(MAX_SWEEP_LIMIT) as usize)
+            .collect();
+
+        for session_id in &sessions {
+            self.cancel_pending(session_id, "swept");
+        }
+        sessions.len() as u64
+    }
+
+    /// Let the buyer use a failed purchase's discount code again
+    fn release_discount(&mut self, pending: &PendingPurchase) {
+        if let Some(code) = &pending.discount_code {
+            self.used_discounts.remove(&(pending.buyer.clone(), code.clone()));
+        }
+    }
+
+    /// Drop a settled or cancelled purchase's reservation
+    fn release_reserved(&mut self, tokens: u128) {
+        self.reserved_tokens = self
+            .reserved_tokens
+            .checked_sub(tokens)
+            .unwrap_or_else(|| env::panic_str("Reserved tokens underflow"));
+    }
+
+    /// Return a failed purchase's tokens to the daily cap, if bought today
+    fn release_daily(&mut self, tokens: u128, created_at_ns: u64) {
+        if day_start(created_at_ns) == self.day_start_ns {
+            self.sold_today = self.sold_today.saturating_sub(tokens);
+        }
+    }
+
+    /// Append to an account's purchase history, dropping the oldest record past the cap
+    fn record_purchase(&mut self, account: &AccountId, record: PurchaseRecord) {
+        let records = self.history.entry(account.clone()).or_default();
+        records.push(record);
+        if records.len() > MAX_HISTORY_PER_ACCOUNT {
+            records.remove(0);
+        }
+    }
+
+    /// yoctoNEAR a failed purchase returns: the purchase amount, plus the
+    /// execution fee when `refund_execution_fee` is set
+    fn refund_amount(&self, amount: u128, execution_fee: u128) -> u128 {
+        if self.refund_execution_fee {
+            amount + execution_fee
+        } else {
+            amount
+        }
+    }
+
+    /// Refund a failed purchase
+    ///
+    /// OutLayer has already kept the execution fee, so topping it up comes
+    /// out of this contract's balance.
+    fn refund(&mut self, buyer: &AccountId, amount: NearToken, execution_fee: NearToken) {
+        let refunded = self.refund_amount(amount.as_yoctonear(), execution_fee.as_yoctonear());
+        if self.refund_execution_fee {
+            self.refunded_execution_fees += execution_fee.as_yoctonear();
+            log!(
+                "Refunding {} yoctoNEAR to {} (purchase amount + {} yoctoNEAR execution fee from contract balance)",
+                refunded,
+                buyer,
+                execution_fee.as_yoctonear()
+            );
+        } else {
+            log!(
+                "Refunding {} yoctoNEAR to {} (purchase amount). Execution fee of {} yoctoNEAR was paid to OutLayer and is not refunded by this contract",
+                refunded,
+                buyer,
+                execution_fee.as_yoctonear()
+            );
+        }
+        self.assert_storage_solvent(refunded);
+        let _ = Promise::new(buyer.clone()).transfer(NearToken::from_yoctonear(refunded));
+    }
+
+    /// Panic if sending `outgoing` yoctoNEAR would leave less than the storage
+    /// stake plus `storage_buffer`
+    fn assert_storage_solvent(&self, outgoing: u128) {
+        let required = (env::storage_usage() as u128)
+            .saturating_mul(env::storage_byte_cost().as_yoctonear())
+            .saturating_add(self.storage_buffer);
+        let remaining = env::account_balance().as_yoctonear().saturating_sub(outgoing);
+        assert!(
+            outgoing <= env::account_balance().as_yoctonear() && remaining >= required,
+            "Sending {} yoctoNEAR would leave {} yoctoNEAR, below the {} yoctoNEAR needed for storage",
+            outgoing,
+            remaining,
+            required
+        );
+    }
+
+    /// Emit a NEP-297 event log, tagged with this sale's `sale_id`
+    fn emit_event(&self, event: &str, mut data: near_sdk::serde_json::Value) {
+        data["sale_id"] = self.sale_id.as_str().into();
+        let event = near_sdk::serde_json::json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_VERSION,
+            "event": event,
+            "data": [data],
+        });
+        env::log_str(&format!("EVENT_JSON:{}", event));
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner,
+            "Only the owner can call this method"
+        );
+    }
+}
+
+/// Start of the UTC day containing `timestamp_ns`
+fn day_start(timestamp_ns: u64) -> u64 {
+    timestamp_ns - timestamp_ns % DAY_NS
+}
+
+/// Convert a purchase amount in yoctoNEAR to tokens in their smallest unit
+///
+/// Multiplies before dividing so fractional NEAR still buys tokens, down to
+/// one smallest unit.
+fn calculate_tokens(purchase_amount: u128, decimals: u8) -> u128 {
+    purchase_amount
+        .checked_mul(TOKENS_PER_NEAR)
+        .unwrap_or_else(|| env::panic_str("Token amount overflow"))
+        / yocto_per_unit(decimals)
+}
+
+/// Convert tokens in their smallest unit to their price in yoctoNEAR
+/// (inverse of `calculate_tokens`)
+fn token_cost(tokens: u128, decimals: u8) -> u128 {
+    tokens
+        .checked_mul(yocto_per_unit(decimals))
+        .unwrap_or_else(|| env::panic_str("Token cost overflow"))
+        / TOKENS_PER_NEAR
+}
+
+/// yoctoNEAR that buy one smallest token unit at one token per NEAR
+fn yocto_per_unit(decimals: u8) -> u128 {
+    ONE_NEAR / 10u128.pow(decimals as u32)
+}
+
+/// Parent of two Merkle nodes: sha256 of both, smaller first
+fn merkle_parent(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+    env::sha256_array([low.as_slice(), high.as_slice()].concat())
+}
+
+/// Decode a 32-byte hash from hex, `None` if malformed
+fn hash_from_hex(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex).as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (byte, pair) in hash.iter_mut().zip(hex.chunks(2)) {
+        let digit = |c: u8| char::from(c).to_digit(16);
+        *byte = (digit(pair[0])? * 16 + digit(pair[1])?) as u8;
+    }
+    Some(hash)
+}
+
+/// Lowercase hex encoding of a hash
+fn hash_to_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Format yoctoNEAR as NEAR without trailing zeros, e.g. "0.11"
+fn format_near(yocto: u128) -> String {
+    format_decimal(yocto, 24)
+}
+
+/// Format `value / 10^decimals` without trailing zeros, e.g. "102.5"
+fn format_decimal(value: u128, decimals: u32) -> String {
+    let scale = 10u128.pow(decimals);
+    let (whole, fraction) = (value / scale, value % scale);
+    if fraction == 0 {
+        whole.to_string()
+    } else {
+        let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+/// `part` as a share of `whole` in basis points, capped at 100%
+fn basis_points(part: u128, whole: u128) -> u16 {
+    if whole == 0 {
+        return 0;
+    }
+    let bps = match part.checked_mul(10_000) {
+        Some(scaled) => scaled / whole,
+        // Only reachable for huge values, where `whole / 10_000` keeps enough precision
+        None => part / (whole / 10_000).max(1),
+    };
+    bps.min(10_000) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const LAUNCHPAD_URL: &str = "https://launchpad.example";
+
+    fn set_context(predecessor: AccountId, deposit: NearToken, timestamp_ns: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(5))
+            .predecessor_account_id(predecessor)
+            .attached_deposit(deposit)
+            .block_timestamp(timestamp_ns)
+            .build());
+    }
+
+    /// Initialized contract owned by `accounts(0)`, bypassing the panicking `Default`
+    fn new_contract(total_supply: u128) -> TokenSaleContract {
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        TokenSaleContract::new(accounts(0), U128(total_supply), LAUNCHPAD_URL.to_string(), None, None)
+    }
+
+    /// Start a 1.01 NEAR purchase: 1 NEAR after the execution reserve, i.e. 100 tokens
+    fn buy(contract: &mut TokenSaleContract, buyer: AccountId, session_id: &str) {
+        set_context(buyer, NearToken::from_millinear(1010), 0);
+        let _ = contract.buy_tokens(session_id.to_string(), None, None, None, None, None, None, None);
+    }
+
+    fn response(verified: bool, error_type: Option<&str>) -> CaptchaResponse {
+        CaptchaResponse {
+            verified,
+            status: None,
+            session_id: "s1".to_string(),
+            error: None,
+            error_type: error_type.map(CaptchaErrorType::parse),
+            solve_latency_ms: None,
+            attempts: None,
+            receipt: None,
+            receipt_timestamp: None,
+            worker_version: None,
+        }
+    }
+
+    /// Deliver the callback `buy` scheduled, with the tokens it reserved
+    fn settle(
+        contract: &mut TokenSaleContract,
+        session_id: &str,
+        result: Result<Option<CaptchaResponse>, PromiseError>,
+    ) -> String {
+        // The callback gets the purchased tokens; the discount bonus is read from pending
+        let result =
+            result.map(|response| response.map(|response| near_sdk::serde_json::to_value(response).unwrap()));
+        settle_raw(contract, session_id, result)
+    }
+
+    /// Settle with the worker output as raw JSON, as OutLayer delivers it
+    fn settle_raw(
+        contract: &mut TokenSaleContract,
+        session_id: &str,
+        result: Result<Option<near_sdk::serde_json::Value>, PromiseError>,
+    ) -> String {
+        let (buyer, tokens) = contract.pending.get(session_id).map_or((accounts(1), 0), |pending| {
+            (pending.buyer.clone(), pending.tokens - pending.bonus_tokens)
+        });
+        set_context(accounts(5), NearToken::from_yoctonear(0), 0);
+        contract.on_captcha_verified(
+            session_id.to_string(),
+            buyer,
+            NearToken::from_millinear(1000),
+            U128(tokens),
+            NearToken::from_millinear(10),
+            None,
+            result,
+        )
+    }
+
+    #[test]
+    #[cfg(not(feature = "mock-captcha"))]
+    fn code_source_view_matches_outlayer_call() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_worker_commit("0123abc".to_string());
+        buy(&mut contract, accounts(1), "s1");
+
+        let sent = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, args, .. }
+                    if method_name == b"request_execution" =>
+                {
+                    let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                    Some(args["code_source"].clone())
+                }
+                _ => None,
+            })
+            .expect("request_execution call");
+        assert_eq!(contract.get_code_source(), sent);
+        assert_eq!(sent["commit"], "0123abc");
+        assert_eq!(sent["build_target"], "wasm32-wasip2");
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach at least")]
+    fn buy_tokens_rejects_small_deposit() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(1), NearToken::from_millinear(100), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough tokens available")]
+    fn buy_tokens_rejects_when_sold_out() {
+        let mut contract = new_contract(50);
+        buy(&mut contract, accounts(1), "s1");
+    }
+
+    #[test]
+    #[should_panic(expected = "already has a purchase being verified")]
+    fn buy_tokens_rejects_duplicate_session() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        buy(&mut contract, accounts(2), "s1");
+    }
+
+    #[test]
+    fn buy_tokens_marks_session_active() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        assert!(contract.is_session_active("s1".to_string()));
+        assert!(!contract.is_session_active("s2".to_string()));
+    }
+
+    #[test]
+    fn overlapping_purchases_cannot_oversell() {
+        let mut contract = new_contract(150);
+        buy(&mut contract, accounts(1), "s1");
+
+        // s1's 100 tokens are reserved, so s2 doesn't fit until s1 settles
+        assert_eq!(
+            contract.quote(U128(NearToken::from_millinear(1010).as_yoctonear())),
+            (U128(0), U128(0))
+        );
+
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("timeout")))));
+        buy(&mut contract, accounts(2), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        assert_eq!(contract.get_stats(), (U128(100), U128(150)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough tokens available")]
+    fn reserved_tokens_block_second_purchase() {
+        let mut contract = new_contract(150);
+        buy(&mut contract, accounts(1), "s1");
+        buy(&mut contract, accounts(2), "s2");
+    }
+
+    #[test]
+    fn partial_fill_exactly_fills_remaining_supply() {
+        let mut contract = new_contract(100);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allow_partial_fill(true);
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert!(message.starts_with("Success! You bought 100 tokens for"), "{}", message);
+        assert_eq!(contract.get_stats(), (U128(100), U128(100)));
+    }
+
+    #[test]
+    fn partial_fill_sells_remaining_supply_and_refunds_rest() {
+        let mut contract = new_contract(60);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allow_partial_fill(true);
+        assert_eq!(contract.quote(U128(NearToken::from_near(2).as_yoctonear())).0, U128(60));
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        // 60 tokens cost 0.6 NEAR of the 1 NEAR purchase
+        let unfilled = NearToken::from_millinear(400).as_yoctonear();
+        assert!(message.contains("60 tokens (partial fill)"), "{}", message);
+        assert!(message.contains(&format!("Refunded {} yoctoNEAR", unfilled)), "{}", message);
+        assert_eq!(contract.get_stats(), (U128(60), U128(60)));
+    }
+
+    #[test]
+    fn partial_fill_leaves_smaller_purchases_untouched() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allow_partial_fill(true);
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert!(message.starts_with("Success! You bought 100 tokens for"), "{}", message);
+        assert_eq!(contract.get_stats(), (U128(100), U128(10_000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "maximum is 256 bytes")]
+    fn buy_tokens_rejects_long_memo() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(1), NearToken::from_near(2), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, Some("x".repeat(257)), None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "User agent is 513 bytes, maximum is 512 bytes")]
+    fn buy_tokens_rejects_long_user_agent() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(1), NearToken::from_near(2), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, Some("x".repeat(513)));
+    }
+
+    #[test]
+    #[cfg(not(feature = "mock-captcha"))]
+    fn client_fingerprint_is_forwarded_to_worker() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(1), NearToken::from_millinear(1010), 0);
+        let _ = contract.buy_tokens(
+            "s1".to_string(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("203.0.113.7".to_string()),
+            Some("Mozilla/5.0".to_string()),
+        );
+
+        let input_data = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, args, .. }
+                    if method_name == b"request_execution" =>
+                {
+                    let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                    Some(args["input_data"].as_str().unwrap().to_string())
+                }
+                _ => None,
+            })
+            .expect("request_execution call");
+        let input: near_sdk::serde_json::Value = near_sdk::serde_json::from_str(&input_data).unwrap();
+        assert_eq!(input["client_ip"], "203.0.113.7");
+        assert_eq!(input["user_agent"], "Mozilla/5.0");
+    }
+
+    #[test]
+    fn verified_callback_emits_purchase_event_with_memo() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(5), NearToken::from_yoctonear(0), 0);
+        contract.on_captcha_verified(
+            "s1".to_string(),
+            accounts(1),
+            NearToken::from_millinear(1000),
+            U128(100),
+            NearToken::from_millinear(10),
+            Some("order-42".to_string()),
+            Ok(Some(near_sdk::serde_json::to_value(response(true, None)).unwrap())),
+        );
+
+        let event = near_sdk::test_utils::get_logs()
+            .into_iter()
+            .find(|log| log.contains("\"token_purchase\""))
+            .expect("token_purchase event");
+        assert!(event.contains("\"memo\":\"order-42\""), "{}", event);
+    }
+
+    #[test]
+    fn get_pending_purchase_reports_elapsed_time() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(2), NearToken::from_yoctonear(0), 5_000);
+        let info = contract.get_pending_purchase("s1".to_string()).unwrap();
+        assert_eq!(info.buyer, accounts(1));
+        assert_eq!(info.amount, U128(NearToken::from_millinear(1000).as_yoctonear()));
+        assert_eq!(info.tokens, U128(100));
+        assert_eq!(info.elapsed_ns, U64(5_000));
+
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        assert!(contract.get_pending_purchase("s1".to_string()).is_none());
+    }
+
+    #[test]
+    fn near_max_supply_sells_without_wrapping() {
+        let mut contract = new_contract(u128::MAX);
+        contract.tokens_sold = u128::MAX - 200;
+        buy(&mut contract, accounts(1), "s1");
+
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert_eq!(contract.get_stats(), (U128(u128::MAX - 100), U128(u128::MAX)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Tokens sold overflow")]
+    fn verified_callback_panics_instead_of_wrapping() {
+        let mut contract = new_contract(u128::MAX);
+        buy(&mut contract, accounts(1), "s1");
+        contract.tokens_sold = u128::MAX - 50;
+
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Reserved tokens underflow")]
+    fn callback_panics_instead_of_underflowing_reservations() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        contract.reserved_tokens = 50;
+
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("timeout")))));
+    }
+
+    #[test]
+    fn get_version_reports_build_and_worker_commit() {
+        let mut contract = new_contract(10_000);
+        contract.set_worker_commit("0123abc".to_string());
+
+        let (version, worker_commit) = contract.get_version();
+
+        assert!(version.starts_with(env!("CARGO_PKG_VERSION")), "{}", version);
+        assert_eq!(worker_commit, "0123abc");
+    }
+
+    #[test]
+    fn callback_logs_worker_version() {
+        let mut contract = new_contract(10_000);
+        assert_eq!(contract.get_contract_version(), env!("CARGO_PKG_VERSION"));
+        buy(&mut contract, accounts(1), "s1");
+
+        // Older workers don't report a version
+        let message = settle_raw(&mut contract, "s1", Ok(Some(near_sdk::serde_json::json!({"verified": true}))));
+        assert!(message.starts_with("Success!"), "{}", message);
+        assert!(!near_sdk::test_utils::get_logs().iter().any(|log| log.contains("Verified by worker")));
+
+        buy(&mut contract, accounts(1), "s2");
+        let mut result = response(true, None);
+        result.worker_version = Some("0.1.0+abc1234".to_string());
+        settle(&mut contract, "s2", Ok(Some(result)));
+        assert!(near_sdk::test_utils::get_logs().contains(&"Verified by worker 0.1.0+abc1234".to_string()));
+    }
+
+    #[test]
+    fn verified_callback_logs_worker_receipt() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let mut result = response(true, None);
+        result.receipt = Some("ab12".to_string());
+        result.receipt_timestamp = Some(1_700_000_000);
+        settle(&mut contract, "s1", Ok(Some(result)));
+
+        let event = near_sdk::test_utils::get_logs()
+            .into_iter()
+            .find(|log| log.contains("\"token_purchase\""))
+            .expect("token_purchase event");
+        assert!(event.contains("\"receipt\":\"ab12\""), "{}", event);
+        assert!(event.contains("\"receipt_timestamp\":1700000000"), "{}", event);
+    }
+
+    #[test]
+    fn daily_cap_resets_at_day_boundary() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_daily_cap(Some(U128(150)));
+
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        assert_eq!(contract.get_daily_remaining(), U128(50));
+        assert_eq!(contract.quote(U128(NearToken::from_millinear(1010).as_yoctonear())).0, U128(0));
+
+        // Next UTC day: the full cap is available again
+        set_context(accounts(1), NearToken::from_millinear(1010), DAY_NS + 1);
+        assert_eq!(contract.get_daily_remaining(), U128(150));
+        let _ = contract.buy_tokens("s2".to_string(), None, None, None, None, None, None, None);
+        assert_eq!(contract.get_daily_remaining(), U128(50));
+    }
+
+    #[test]
+    fn purchase_cooldown_starts_at_verified_purchase() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_purchase_cooldown_ns(60_000_000_000);
+        let attached = U128(NearToken::from_millinear(1010).as_yoctonear());
+
+        // A failed attempt doesn't start the cooldown
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("wrong_answer")))));
+        assert_eq!(contract.can_buy(accounts(1), attached, None, None), None);
+
+        buy(&mut contract, accounts(1), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        set_context(accounts(1), NearToken::from_yoctonear(0), 60_000_000_000 - 1);
+        assert_eq!(
+            contract.can_buy(accounts(1), attached, None, None),
+            Some("bob must wait 1 more seconds before buying again".to_string())
+        );
+        assert_eq!(contract.can_buy(accounts(2), attached, None, None), None);
+
+        set_context(accounts(1), NearToken::from_millinear(1010), 60_000_000_000);
+        let _ = contract.buy_tokens("s3".to_string(), None, None, None, None, None, None, None);
+        assert!(contract.is_session_active("s3".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Daily cap reached")]
+    fn daily_cap_rejects_excess_purchase() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_daily_cap(Some(U128(150)));
+
+        buy(&mut contract, accounts(1), "s1");
+        set_context(accounts(2), NearToken::from_millinear(1010), DAY_NS - 1);
+        let _ = contract.buy_tokens("s2".to_string(), None, None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn failed_purchase_returns_tokens_to_daily_cap() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_daily_cap(Some(U128(150)));
+
+        buy(&mut contract, accounts(1), "s1");
+        assert_eq!(contract.get_daily_remaining(), U128(50));
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("timeout")))));
+        assert_eq!(contract.get_daily_remaining(), U128(150));
+    }
+
+    #[test]
+    fn sale_stats_break_down_supply() {
+        let mut contract = new_contract(1_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        buy(&mut contract, accounts(2), "s2");
+
+        let stats = contract.get_sale_stats();
+        assert_eq!(stats.tokens_sold, U128(100));
+        assert_eq!(stats.total_supply, U128(1_000));
+        assert_eq!(stats.reserved, U128(100));
+        assert_eq!(stats.remaining, U128(800));
+        assert_eq!(contract.get_stats(), (stats.tokens_sold, stats.total_supply));
+    }
+
+    #[test]
+    fn metrics_count_verified_purchases_only() {
+        let mut contract = new_contract(1_000);
+
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        buy(&mut contract, accounts(1), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        buy(&mut contract, accounts(2), "s3");
+        settle(&mut contract, "s3", Ok(Some(response(false, Some("wrong_answer")))));
+        buy(&mut contract, accounts(3), "s4");
+
+        let metrics = contract.get_metrics();
+        assert_eq!(metrics.tokens_sold, U128(200));
+        assert_eq!(metrics.total_supply, U128(1_000));
+        assert_eq!(metrics.remaining, U128(700));
+        assert_eq!(metrics.buyer_count, 1);
+        assert_eq!(metrics.near_raised, U128(NearToken::from_near(2).as_yoctonear()));
+        assert_eq!(metrics.progress_bps, 2_000);
+        assert!(!metrics.paused);
+        assert!(metrics.sale_active);
+    }
+
+    #[test]
+    fn purchased_totals_are_returned_in_request_order() {
+        let mut contract = new_contract(1_000);
+
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        buy(&mut contract, accounts(1), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        buy(&mut contract, accounts(2), "s3");
+        settle(&mut contract, "s3", Ok(Some(response(true, None))));
+        buy(&mut contract, accounts(3), "s4");
+        settle(&mut contract, "s4", Ok(Some(response(false, Some("wrong_answer")))));
+
+        assert_eq!(contract.get_purchased(accounts(1)), U128(200));
+        assert_eq!(
+            contract.get_purchased_batch(vec![accounts(2), accounts(3), accounts(1), accounts(4)]),
+            vec![U128(100), U128(0), U128(200), U128(0)]
+        );
+        assert!(contract.get_purchased_batch(vec![]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "At most 50 accounts per batch")]
+    fn purchased_batch_is_bounded() {
+        let contract = new_contract(1_000);
+        contract.get_purchased_batch(vec![accounts(1); MAX_BATCH_SIZE + 1]);
+    }
+
+    #[test]
+    fn basis_points_handles_edge_values() {
+        assert_eq!(basis_points(0, 0), 0);
+        assert_eq!(basis_points(1, 3), 3_333);
+        assert_eq!(basis_points(u128::MAX, u128::MAX), 10_000);
+        assert_eq!(basis_points(u128::MAX / 2, u128::MAX), 5_000);
+    }
+
+    #[test]
+    fn worker_repo_is_owner_configurable() {
+        let mut contract = new_contract(10_000);
+        assert_eq!(contract.get_config().worker_repo, "https://github.com/zavodil/captcha-ark");
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_worker_repo("https://github.com/fork/captcha-ark".to_string());
+        assert_eq!(contract.get_config().worker_repo, "https://github.com/fork/captcha-ark");
+    }
+
+    #[test]
+    #[should_panic(expected = "Worker repo must start with https://github.com/")]
+    fn non_github_worker_repo_is_rejected() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_worker_repo("http://github.com/fork/captcha-ark".to_string());
+    }
+
+    #[test]
+    fn build_target_is_owner_configurable() {
+        let mut contract = new_contract(10_000);
+        assert_eq!(contract.get_config().build_target, "wasm32-wasip2");
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_build_target("wasm32-wasip1".to_string());
+        assert_eq!(contract.get_config().build_target, "wasm32-wasip1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported build target")]
+    fn unknown_build_target_is_rejected() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_build_target("wasm32-unknown-unknown".to_string());
+    }
+
+    #[test]
+    fn response_format_is_owner_configurable() {
+        let mut contract = new_contract(10_000);
+        assert_eq!(contract.get_config().response_format, "Json");
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_response_format("Text".to_string());
+        assert_eq!(contract.get_config().response_format, "Text");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown response format")]
+    fn unknown_response_format_is_rejected() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_response_format("Raw".to_string());
+    }
+
+    /// Start a 1.01 NEAR purchase (100 tokens) redeeming a discount code
+    fn buy_with_code(contract: &mut TokenSaleContract, buyer: AccountId, session_id: &str, code: &str) {
+        set_context(buyer, NearToken::from_millinear(1010), 0);
+        let _ = contract.buy_tokens(session_id.to_string(), None, None, Some(code.to_string()), None, None, None, None);
+    }
+
+    #[test]
+    fn discount_code_grants_bonus_once_per_account() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("LAUNCH".to_string(), 1_500);
+        assert_eq!(contract.get_discount_bps("LAUNCH".to_string()), Some(1_500));
+        assert_eq!(contract.get_discount_bps("OTHER".to_string()), None);
+
+        buy_with_code(&mut contract, accounts(1), "s1", "LAUNCH");
+        assert_eq!(contract.get_pending_purchase("s1".to_string()).unwrap().tokens, U128(115));
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert!(message.contains("bought 115 tokens"), "{}", message);
+        assert_eq!(contract.get_stats().0, U128(115));
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains(r#""bonus_tokens":"15""#)));
+
+        // Another account may still use it
+        buy_with_code(&mut contract, accounts(2), "s2", "LAUNCH");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        assert_eq!(contract.get_stats().0, U128(230));
+    }
+
+    #[test]
+    #[should_panic(expected = "Discount code LAUNCH was already used by bob")]
+    fn discount_code_cannot_be_reused() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("LAUNCH".to_string(), 1_500);
+
+        buy_with_code(&mut contract, accounts(1), "s1", "LAUNCH");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        buy_with_code(&mut contract, accounts(1), "s2", "LAUNCH");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown discount code OLD")]
+    fn removed_discount_code_is_rejected() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("OLD".to_string(), 1_000);
+        contract.remove_discount_code("OLD".to_string());
+
+        buy_with_code(&mut contract, accounts(1), "s1", "OLD");
+    }
+
+    #[test]
+    fn failed_purchase_frees_discount_code() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("LAUNCH".to_string(), 1_500);
+
+        buy_with_code(&mut contract, accounts(1), "s1", "LAUNCH");
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("wrong_answer")))));
+        assert_eq!(contract.get_stats().0, U128(0));
+
+        buy_with_code(&mut contract, accounts(1), "s2", "LAUNCH");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        assert_eq!(contract.get_stats().0, U128(115));
+    }
+
+    #[test]
+    fn discount_bonus_is_capped_by_supply() {
+        let mut contract = new_contract(110);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("LAUNCH".to_string(), 5_000);
+
+        buy_with_code(&mut contract, accounts(1), "s1", "LAUNCH");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert_eq!(contract.get_stats(), (U128(110), U128(110)));
+    }
+
+    #[test]
+    fn captcha_difficulty_escalates_and_resets() {
+        let mut contract = new_contract(10_000);
+        assert_eq!(contract.get_captcha_difficulty(accounts(1)), 0);
+
+        for (i, error_type) in ["wrong_answer", "timeout", "network_error"].into_iter().enumerate() {
+            let session_id = format!("s{}", i);
+            buy(&mut contract, accounts(1), &session_id);
+            settle(&mut contract, &session_id, Ok(Some(response(false, Some(error_type)))));
+        }
+        // Launchpad failures don't count against the buyer
+        assert_eq!(contract.get_captcha_difficulty(accounts(1)), 2);
+
+        buy(&mut contract, accounts(1), "s3");
+        settle(&mut contract, "s3", Ok(Some(response(true, None))));
+        assert_eq!(contract.get_captcha_difficulty(accounts(1)), 0);
+    }
+
+    #[test]
+    fn captcha_difficulty_is_capped() {
+        let mut contract = new_contract(10_000);
+        for i in 0..MAX_CAPTCHA_DIFFICULTY + 3 {
+            let session_id = format!("s{}", i);
+            buy(&mut contract, accounts(1), &session_id);
+            settle(&mut contract, &session_id, Ok(Some(response(false, Some("wrong_answer")))));
+        }
+
+        assert_eq!(contract.get_captcha_difficulty(accounts(1)), MAX_CAPTCHA_DIFFICULTY);
+    }
+
+    /// yoctoNEAR transferred to `account` by the last call
+    fn transfers_to(account: &AccountId) -> Vec<u128> {
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .filter(|receipt| &receipt.receiver_id == account)
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                near_sdk::mock::MockAction::Transfer { deposit, .. } => Some(deposit.as_yoctonear()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn execution_fee_is_kept_on_refund_by_default() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("wrong_answer")))));
+
+        assert_eq!(transfers_to(&accounts(1)), vec![NearToken::from_millinear(1000).as_yoctonear()]);
+        let record = &contract.get_history(accounts(1), 0, 10)[0];
+        assert_eq!(record.execution_fee, U128(EXECUTION_RESERVE));
+        assert_eq!(record.refunded, U128(NearToken::from_millinear(1000).as_yoctonear()));
+        assert_eq!(contract.get_execution_accounting().refunded_execution_fees, U128(0));
+    }
+
+    #[test]
+    fn execution_fee_can_be_refunded_from_contract_balance() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_refund_execution_fee(true);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("wrong_answer")))));
+
+        assert_eq!(transfers_to(&accounts(1)), vec![NearToken::from_millinear(1010).as_yoctonear()]);
+        assert_eq!(
+            contract.get_history(accounts(1), 0, 10)[0].refunded,
+            U128(NearToken::from_millinear(1010).as_yoctonear())
+        );
+        let accounting = contract.get_execution_accounting();
+        assert!(accounting.refund_execution_fee);
+        assert_eq!(accounting.refunded_execution_fees, U128(EXECUTION_RESERVE));
+
+        // Verified purchases refund nothing
+        buy(&mut contract, accounts(1), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        assert_eq!(contract.get_history(accounts(1), 0, 10)[1].refunded, U128(0));
+    }
+
+    /// Start the same purchase as `buy`, with refunds sent to `sponsor`
+    fn sponsored_buy(contract: &mut TokenSaleContract, buyer: AccountId, sponsor: AccountId, session_id: &str) {
+        set_context(buyer, NearToken::from_millinear(1010), 0);
+        let _ = contract.buy_tokens(session_id.to_string(), None, None, None, None, Some(sponsor), None, None);
+    }
+
+    #[test]
+    fn failed_sponsored_purchase_refunds_sponsor() {
+        let mut contract = new_contract(10_000);
+        sponsored_buy(&mut contract, accounts(1), accounts(2), "s1");
+        assert_eq!(contract.get_pending_purchase("s1".to_string()).unwrap().refund_to, accounts(2));
+
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("wrong_answer")))));
+
+        assert_eq!(transfers_to(&accounts(2)), vec![NearToken::from_millinear(1000).as_yoctonear()]);
+        assert!(transfers_to(&accounts(1)).is_empty());
+        assert_eq!(contract.get_purchased(accounts(1)), U128(0));
+    }
+
+    #[test]
+    fn sponsored_purchase_credits_buyer_and_refunds_unfilled_to_sponsor() {
+        let mut contract = new_contract(60);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allow_partial_fill(true);
+        sponsored_buy(&mut contract, accounts(1), accounts(2), "s1");
+
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert_eq!(contract.get_purchased(accounts(1)), U128(60));
+        assert_eq!(contract.get_purchased(accounts(2)), U128(0));
+        assert_eq!(transfers_to(&accounts(2)), vec![NearToken::from_millinear(400).as_yoctonear()]);
+        assert!(transfers_to(&accounts(1)).is_empty());
+    }
+
+    #[test]
+    fn force_refund_pays_sponsor() {
+        let mut contract = new_contract(10_000);
+        sponsored_buy(&mut contract, accounts(1), accounts(2), "s1");
+        set_context(accounts(0), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+
+        contract.force_refund("s1".to_string());
+
+        assert_eq!(transfers_to(&accounts(2)), vec![NearToken::from_millinear(1000).as_yoctonear()]);
+        assert!(transfers_to(&accounts(1)).is_empty());
+    }
+
+    /// Context for settling a purchase with `balance` yoctoNEAR in the contract
+    fn set_balance_context(balance: u128) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(5))
+            .predecessor_account_id(accounts(5))
+            .account_balance(NearToken::from_yoctonear(balance))
+            .build());
+    }
+
+    #[test]
+    fn storage_solvency_boundary() {
+        let contract = new_contract(10_000);
+        set_balance_context(0);
+        let required = env::storage_usage() as u128 * env::storage_byte_cost().as_yoctonear() + DEFAULT_STORAGE_BUFFER;
+        set_balance_context(required + 1_000);
+
+        contract.assert_storage_solvent(1_000);
+        contract.assert_storage_solvent(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "needed for storage")]
+    fn storage_solvency_rejects_one_yocto_past_boundary() {
+        let contract = new_contract(10_000);
+        set_balance_context(0);
+        let required = env::storage_usage() as u128 * env::storage_byte_cost().as_yoctonear() + DEFAULT_STORAGE_BUFFER;
+        set_balance_context(required + 1_000);
+
+        contract.assert_storage_solvent(1_001);
+    }
+
+    #[test]
+    #[should_panic(expected = "needed for storage")]
+    fn refund_that_would_dip_into_storage_stake_panics() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        // Just enough for the 1 NEAR refund but not the buffer
+        set_balance_context(NearToken::from_near(4).as_yoctonear());
+        contract.on_captcha_verified(
+            "s1".to_string(),
+            accounts(1),
+            NearToken::from_millinear(1000),
+            U128(100),
+            NearToken::from_millinear(10),
+            None,
+            Ok(Some(near_sdk::serde_json::to_value(response(false, Some("wrong_answer"))).unwrap())),
+        );
+    }
+
+    #[test]
+    fn storage_buffer_is_owner_configurable() {
+        let mut contract = new_contract(10_000);
+        assert_eq!(contract.get_config().storage_buffer, U128(DEFAULT_STORAGE_BUFFER));
+        contract.set_storage_buffer(U128(0));
+        assert_eq!(contract.get_config().storage_buffer, U128(0));
+    }
+
+    #[test]
+    fn compact_state_removes_orphaned_sessions() {
+        let mut contract = new_contract(1_000);
+        buy(&mut contract, accounts(1), "s1");
+        contract.pending_sessions.insert("orphan".to_string(), 0);
+        contract.pending_sessions.flush();
+        let storage_before = env::storage_usage();
+
+        let reclaimed = contract.compact_state(10);
+
+        assert!(reclaimed > 0);
+        assert_eq!(env::storage_usage(), storage_before - reclaimed);
+        assert!(!contract.pending_sessions.contains_key("orphan"));
+        assert!(contract.is_session_active("s1".to_string()));
+        assert_eq!(contract.get_oldest_pending(10).len(), 1);
+        assert_eq!(contract.compact_state(10), 0);
+    }
+
+    #[test]
+    fn interpret_captcha_covers_all_outcomes() {
+        assert_eq!(interpret_captcha(&Ok(Some(response(true, None)))), CaptchaOutcome::Verified);
+        assert_eq!(
+            interpret_captcha(&Ok(Some(response(false, Some("wrong_answer"))))),
+            CaptchaOutcome::Failed(CaptchaErrorType::WrongAnswer)
+        );
+        assert_eq!(
+            interpret_captcha(&Ok(Some(response(false, None)))),
+            CaptchaOutcome::Failed(CaptchaErrorType::Unknown)
+        );
+        assert_eq!(interpret_captcha(&Ok(None)), CaptchaOutcome::ExecutionFailed);
+        assert_eq!(interpret_captcha(&Err(PromiseError::Failed)), CaptchaOutcome::SystemError);
+    }
+
+    #[test]
+    fn unknown_error_type_refunds_with_generic_message() {
+        assert_eq!(CaptchaErrorType::parse("binding_mismatch"), CaptchaErrorType::BindingMismatch);
+        assert_eq!(CaptchaErrorType::parse("from_a_newer_worker"), CaptchaErrorType::Unknown);
+
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        let message = settle_raw(
+            &mut contract,
+            "s1",
+            Ok(Some(near_sdk::serde_json::json!({"verified": false, "error_type": "from_a_newer_worker"}))),
+        );
+
+        assert!(message.contains("CAPTCHA verification failed"), "{}", message);
+        assert_eq!(contract.get_history(accounts(1), 0, 10)[0].outcome, "unknown");
+    }
+
+    #[test]
+    fn partial_worker_response_is_tolerated() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle_raw(&mut contract, "s1", Ok(Some(near_sdk::serde_json::json!({"status": "verified"}))));
+
+        assert!(message.starts_with("Success! You bought 100 tokens"), "{}", message);
+        assert_eq!(contract.get_stats().0, U128(100));
+    }
+
+    #[test]
+    fn malformed_worker_response_is_refunded_as_system_error() {
+        // A fresh session each round: the mocked storage outlives each contract
+        for (session_id, raw) in [
+            ("s1", near_sdk::serde_json::json!({"verified": "yes", "session_id": "s1"})),
+            ("s2", near_sdk::serde_json::json!("not an object")),
+        ] {
+            let mut contract = new_contract(10_000);
+            buy(&mut contract, accounts(1), session_id);
+
+            let message = settle_raw(&mut contract, session_id, Ok(Some(raw)));
+
+            assert!(message.contains("Refunded"), "{}", message);
+            assert!(message.contains("Malformed worker response"), "{}", message);
+            assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("Malformed worker response")));
+            assert_eq!(contract.get_stats().0, U128(0));
+            assert_eq!(contract.get_history(accounts(1), 0, 1)[0].outcome, "system_error");
+            assert!(!contract.is_session_active(session_id.to_string()));
+        }
+    }
+
+    #[test]
+    fn verified_callback_credits_tokens() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert!(message.starts_with("Success! You bought 100 tokens"));
+        assert_eq!(contract.get_stats(), (U128(100), U128(10_000)));
+        assert!(!contract.is_session_active("s1".to_string()));
+    }
+
+    #[test]
+    fn verified_callback_credits_tokens_computed_at_buy_time() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        // A different amount at callback time (e.g. after a price change) is not re-priced
+        set_context(accounts(5), NearToken::from_yoctonear(0), 0);
+        let message = contract.on_captcha_verified(
+            "s1".to_string(),
+            accounts(1),
+            NearToken::from_near(5),
+            U128(100),
+            NearToken::from_millinear(10),
+            None,
+            Ok(Some(near_sdk::serde_json::to_value(response(true, None)).unwrap())),
+        );
+
+        assert!(message.starts_with("Success! You bought 100 tokens"), "{}", message);
+        assert_eq!(contract.get_stats(), (U128(100), U128(10_000)));
+        assert_eq!(contract.get_history(accounts(1), 0, 10)[0].tokens, U128(100));
+    }
+
+    #[test]
+    fn failed_callback_refunds_with_matching_message() {
+        let cases = [
+            (Some("wrong_answer"), "Wrong answer"),
+            (Some("timeout"), "CAPTCHA timeout"),
+            (Some("network_error"), "Network error"),
+            (Some("auth_error"), "rejected the verifier's credentials"),
+            (None, "CAPTCHA verification failed"),
+        ];
+
+        // A fresh session each round: the mocked storage outlives each contract
+        for (round, (error_type, expected)) in cases.into_iter().enumerate() {
+            let session_id = format!("s{}", round);
+            let mut contract = new_contract(10_000);
+            buy(&mut contract, accounts(1), &session_id);
+
+            let message = settle(&mut contract, &session_id, Ok(Some(response(false, error_type))));
+
+            assert!(message.contains(expected), "{}", message);
+            assert!(message.contains("Refunded"), "{}", message);
+            assert_eq!(contract.get_stats().0, U128(0));
+            assert!(!contract.is_session_active(session_id));
+        }
+    }
+
+    #[test]
+    fn worker_status_maps_to_refund_message() {
+        let cases = [
+            ("wrong_answer", "Wrong answer"),
+            ("timeout", "CAPTCHA timeout"),
+            ("rate_limited", "rate limiting"),
+            ("network_error", "Network error"),
+            ("signature_invalid", "signature check"),
+            ("expired", "challenge expired"),
+            ("binding_mismatch", "didn't match this purchase"),
+            ("redirect_error", "won't follow"),
+            ("system_error", "CAPTCHA verification failed"),
+        ];
+
+        // A fresh session each round: the mocked storage outlives each contract
+        for (status, expected) in cases {
+            let mut contract = new_contract(10_000);
+            buy(&mut contract, accounts(1), status);
+
+            // `status` wins over a conflicting legacy `error_type`
+            let mut result = response(false, Some("timeout"));
+            result.status = Some(status.to_string());
+            let message = settle(&mut contract, status, Ok(Some(result)));
+
+            assert!(message.contains(expected), "{}: {}", status, message);
+            assert!(message.contains("Refunded"), "{}", message);
+            assert_eq!(contract.get_stats().0, U128(0));
+        }
+    }
+
+    #[test]
+    fn worker_status_verified_credits_tokens() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let mut result = response(true, None);
+        result.status = Some("verified".to_string());
+        let message = settle(&mut contract, "s1", Ok(Some(result)));
+
+        assert!(message.starts_with("Success! You bought 100 tokens"), "{}", message);
+    }
+
+    #[test]
+    fn settled_purchases_are_recorded_in_history() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("timeout")))));
+        buy(&mut contract, accounts(1), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+
+        let history = contract.get_history(accounts(1), 0, 10);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].outcome, "timeout");
+        assert_eq!(history[0].tokens, U128(0));
+        assert_eq!(history[1].session_id, "s2");
+        assert_eq!(history[1].outcome, "verified");
+        assert_eq!(history[1].tokens, U128(100));
+        assert_eq!(contract.get_history(accounts(1), 1, 10).len(), 1);
+        assert!(contract.get_history(accounts(2), 0, 10).is_empty());
+    }
+
+    #[test]
+    fn history_is_bounded_per_account() {
+        let mut contract = new_contract(u128::MAX);
+        for i in 0..MAX_HISTORY_PER_ACCOUNT + 5 {
+            let session_id = format!("s{}", i);
+            buy(&mut contract, accounts(1), &session_id);
+            settle(&mut contract, &session_id, Ok(None));
+        }
+
+        let history = contract.get_history(accounts(1), 0, MAX_PAGE_LIMIT);
+        assert_eq!(history.len(), MAX_HISTORY_PER_ACCOUNT);
+        assert_eq!(history[0].session_id, "s5");
+    }
+
+    #[test]
+    fn execution_failure_refunds_and_counts() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle(&mut contract, "s1", Ok(None));
+
+        assert!(message.contains("execution failed"));
+        assert_eq!(contract.get_execution_failures(), 1);
+        assert_eq!(contract.get_stats().0, U128(0));
+    }
+
+    #[test]
+    fn promise_error_refunds() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let message = settle(&mut contract, "s1", Err(PromiseError::Failed));
+
+        assert!(message.starts_with("System error. Refunded"));
+        assert_eq!(contract.get_stats().0, U128(0));
+    }
+
+    #[test]
+    fn callback_for_settled_session_is_ignored() {
+        let mut contract = new_contract(10_000);
+
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert!(message.contains("already settled"));
+        assert_eq!(contract.get_stats().0, U128(0));
+    }
+
+    #[test]
+    fn duplicate_callback_credits_once() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        let callback = |contract: &mut TokenSaleContract| {
+            set_context(accounts(5), NearToken::from_yoctonear(0), 0);
+            contract.on_captcha_verified(
+                "s1".to_string(),
+                accounts(1),
+                NearToken::from_millinear(1000),
+                U128(100),
+                NearToken::from_millinear(10),
+                None,
+                Ok(Some(near_sdk::serde_json::to_value(response(true, None)).unwrap())),
+            )
+        };
+        assert!(callback(&mut contract).starts_with("Success!"));
+        let message = callback(&mut contract);
+
+        assert!(message.contains("already settled"), "{}", message);
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("Ignoring callback for session s1")));
+        assert_eq!(contract.get_stats().0, U128(100));
+        assert_eq!(contract.get_history(accounts(1), 0, 10).len(), 1);
+    }
+
+    #[test]
+    fn oldest_pending_sessions_come_first() {
+        let mut contract = new_contract(10_000);
+        for (session_id, timestamp) in [("c", 300), ("b", 100), ("a", 300), ("d", 200)] {
+            set_context(accounts(1), NearToken::from_millinear(1010), timestamp);
+            let _ = contract.buy_tokens(session_id.to_string(), None, None, None, None, None, None, None);
+        }
+
+        let oldest = contract.get_oldest_pending(10);
+        assert_eq!(
+            oldest,
+            vec![
+                ("b".to_string(), U64(100)),
+                ("d".to_string(), U64(200)),
+                ("a".to_string(), U64(300)),
+                ("c".to_string(), U64(300)),
+            ]
+        );
+        assert_eq!(contract.get_oldest_pending(1), oldest[..1].to_vec());
+
+        // Settled and force-refunded sessions drop out
+        settle(&mut contract, "b", Ok(Some(response(true, None))));
+        set_context(accounts(0), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS + 200);
+        contract.force_refund("d".to_string());
+        assert_eq!(
+            contract.get_oldest_pending(10),
+            vec![("a".to_string(), U64(300)), ("c".to_string(), U64(300))]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "force refund allowed after")]
+    fn force_refund_rejects_too_early() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS - 1);
+        contract.force_refund("s1".to_string());
+    }
+
+    #[test]
+    fn force_refund_clears_stuck_purchase() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        contract.force_refund("s1".to_string());
+        assert!(!contract.is_session_active("s1".to_string()));
+
+        // A late callback must not credit or refund again
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        assert!(message.contains("already settled"));
+        assert_eq!(contract.get_stats().0, U128(0));
+    }
+
+    #[test]
+    fn buyer_can_cancel_stuck_purchase() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(1), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        contract.cancel_stuck_purchase("s1".to_string());
+        assert_eq!(transfers_to(&accounts(1)), vec![NearToken::from_millinear(1000).as_yoctonear()]);
+        assert!(!contract.is_session_active("s1".to_string()));
+        assert_eq!(contract.get_history(accounts(1), 0, 10)[0].outcome, "cancelled");
+        assert_eq!(contract.get_sale_stats().reserved, U128(0));
+
+        // The callback arriving late neither credits nor refunds again
+        let message = settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        assert!(message.contains("already settled"), "{}", message);
+        assert_eq!(transfers_to(&accounts(1)), Vec::<u128>::new());
+        assert_eq!(contract.get_purchased(accounts(1)), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the buyer can cancel this purchase")]
+    fn only_buyer_can_cancel_stuck_purchase() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(2), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        contract.cancel_stuck_purchase("s1".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "cancellation allowed after")]
+    fn stuck_purchase_cancel_waits_for_timeout() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        set_context(accounts(1), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS - 1);
+        contract.cancel_stuck_purchase("s1".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Session s1 was already used, start a new session")]
+    fn cancelled_session_cannot_be_reused() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        set_context(accounts(1), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        contract.cancel_stuck_purchase("s1".to_string());
+
+        buy(&mut contract, accounts(1), "s1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Session s1 was already used, start a new session")]
+    fn settled_session_cannot_be_reused() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        // The launchpad would replay the solved result for the same nonce
+        buy(&mut contract, accounts(1), "s1");
+    }
+
+    #[test]
+    fn sweep_refunds_only_expired_purchases() {
+        let mut contract = new_contract(10_000);
+        for (session_id, timestamp) in [("stale1", 0), ("fresh", DEFAULT_PENDING_TIMEOUT_NS), ("stale2", 100)] {
+            set_context(accounts(1), NearToken::from_millinear(1010), timestamp);
+            let _ = contract.buy_tokens(session_id.to_string(), None, None, None, None, None, None, None);
+        }
+        assert_eq!(contract.get_sale_stats().reserved, U128(300));
+
+        // Anyone may sweep; "fresh" is not yet past the timeout
+        set_context(accounts(3), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS + 100);
+        assert_eq!(contract.sweep_expired(10), 2);
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains(r#""event":"swept""#)));
+        assert!(!contract.is_session_active("stale1".to_string()));
+        assert!(!contract.is_session_active("stale2".to_string()));
+        assert!(contract.is_session_active("fresh".to_string()));
+        assert_eq!(contract.get_sale_stats().reserved, U128(100));
+        assert_eq!(contract.get_history(accounts(1), 0, 10)[0].outcome, "swept");
+
+        assert_eq!(contract.sweep_expired(10), 0);
+    }
+
+    #[test]
+    fn sweep_respects_limit() {
+        let mut contract = new_contract(10_000);
+        for session_id in ["s1", "s2", "s3"] {
+            buy(&mut contract, accounts(1), session_id);
+        }
+
+        set_context(accounts(3), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        assert_eq!(contract.sweep_expired(2), 2);
+        assert_eq!(contract.sweep_expired(2), 1);
+        assert_eq!(contract.get_oldest_pending(10), vec![]);
+    }
+
+    #[test]
+    fn owner_sweeps_reservations_before_cutoff_in_pages() {
+        let mut contract = new_contract(10_000);
+        for i in 0..MAX_SWEEP_LIMIT + 2 {
+            set_context(accounts(1), NearToken::from_millinear(1010), 100);
+            let _ = contract.buy_tokens(format!("old{}", i), None, None, None, None, None, None, None);
+        }
+        set_context(accounts(2), NearToken::from_millinear(1010), 500);
+        let _ = contract.buy_tokens("new".to_string(), None, None, None, None, None, None, None);
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS + 1_000);
+        assert_eq!(contract.sweep_expired_reservations(500), MAX_SWEEP_LIMIT);
+        let swept_events = near_sdk::test_utils::get_logs()
+            .iter()
+            .filter(|log| log.contains(r#""event":"swept""#))
+            .count();
+        assert_eq!(swept_events as u64, MAX_SWEEP_LIMIT);
+        assert_eq!(transfers_to(&accounts(1)).len() as u64, MAX_SWEEP_LIMIT);
+
+        assert_eq!(contract.sweep_expired_reservations(500), 2);
+        assert_eq!(contract.sweep_expired_reservations(500), 0);
+        assert!(contract.is_session_active("new".to_string()));
+        assert_eq!(contract.get_sale_stats().reserved, U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cutoff must be at least")]
+    fn sweep_reservations_cutoff_must_respect_pending_timeout() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        contract.sweep_expired_reservations(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner")]
+    fn sweep_reservations_is_owner_only() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(1), NearToken::from_yoctonear(0), DEFAULT_PENDING_TIMEOUT_NS);
+        contract.sweep_expired_reservations(0);
+    }
+
+    #[test]
+    fn supply_can_be_increased_and_decreased() {
+        let mut contract = new_contract(10_000);
+        contract.increase_supply(U128(500));
+        assert_eq!(contract.get_stats().1, U128(10_500));
+
+        contract.decrease_supply(U128(10_000));
+        assert_eq!(contract.get_stats().1, U128(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot decrease supply below committed tokens")]
+    fn supply_cannot_drop_below_sold() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.decrease_supply(U128(9_950));
+    }
+
+    #[test]
+    fn total_supply_can_be_set() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_total_supply(U128(20_000));
+        assert_eq!(contract.get_stats(), (U128(100), U128(20_000)));
+        assert_eq!(contract.get_remaining_supply(), U128(19_900));
+        assert!(near_sdk::test_utils::get_logs()[0].contains(r#""event":"supply_updated""#));
+
+        // Down to exactly what was sold
+        contract.set_total_supply(U128(100));
+        assert_eq!(contract.get_remaining_supply(), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot set supply below committed tokens (200)")]
+    fn total_supply_cannot_be_set_below_committed() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        // Reserved by a pending purchase
+        buy(&mut contract, accounts(2), "s2");
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_total_supply(U128(199));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner")]
+    fn supply_changes_are_owner_only() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        contract.increase_supply(U128(1));
+    }
+
+    #[test]
+    fn quote_matches_purchase_branches() {
+        let contract = new_contract(10_000);
+        let yocto = |millinear: u128| U128(NearToken::from_millinear(millinear).as_yoctonear());
+
+        // Below the 0.11 NEAR minimum
+        assert_eq!(contract.quote(yocto(109)), (U128(0), U128(0)));
+        // From the minimum up: everything except the 0.01 NEAR reserve
+        assert_eq!(contract.quote(yocto(110)), (U128(10), yocto(10)));
+        assert_eq!(contract.quote(yocto(150)), (U128(14), yocto(10)));
+        assert_eq!(contract.quote(yocto(200)), (U128(19), yocto(10)));
+        assert_eq!(contract.quote(yocto(2010)), (U128(200), yocto(10)));
+    }
+
+    #[test]
+    fn exact_minimum_deposit_buys_the_minimum_purchase() {
+        let mut contract = new_contract(10_000);
+        let min_total = contract.get_min_deposit().0;
+
+        // Exactly the minimum: 0.1 NEAR buys 10 tokens, 0.01 NEAR goes to execution
+        assert_eq!(contract.quote(U128(min_total)), (U128(10), U128(EXECUTION_RESERVE)));
+        // One yoctoNEAR more goes to the purchase, not the execution fee
+        assert_eq!(contract.quote(U128(min_total + 1)), (U128(10), U128(EXECUTION_RESERVE)));
+
+        for (session_id, attached) in [("s1", min_total), ("s2", min_total + 1)] {
+            set_context(accounts(1), NearToken::from_yoctonear(attached), 0);
+            let _ = contract.buy_tokens(session_id.to_string(), None, None, None, None, None, None, None);
+            let pending = contract.pending.get(session_id).unwrap();
+            assert_eq!(pending.amount, attached - EXECUTION_RESERVE);
+            assert_eq!(pending.execution_fee, EXECUTION_RESERVE);
+            assert_eq!(pending.tokens, 10);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "buys no tokens")]
+    fn purchase_worth_no_tokens_is_rejected() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_min_purchase(U128(1));
+
+        set_context(accounts(1), NearToken::from_yoctonear(contract.get_min_deposit().0), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn required_deposit_is_inverse_of_quote() {
+        let contract = new_contract(10_000);
+        let yocto = |millinear: u128| U128(NearToken::from_millinear(millinear).as_yoctonear());
+
+        assert_eq!(contract.get_required_deposit(U128(5)), yocto(110));
+        assert_eq!(contract.get_required_deposit(U128(10)), yocto(110));
+        assert_eq!(contract.get_required_deposit(U128(15)), yocto(160));
+        assert_eq!(contract.get_required_deposit(U128(150)), yocto(1510));
+
+        for tokens in [1, 10, 11, 19, 20, 150, 1000] {
+            let required = contract.get_required_deposit(U128(tokens)).0;
+            assert!(contract.quote(U128(required)).0 .0 >= tokens);
+            assert!(contract.quote(U128(required - 1)).0 .0 < tokens);
+        }
+    }
+
+    #[test]
+    fn purchase_above_max_is_capped_and_excess_refunded() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_max_purchase_near(Some(U128(NearToken::from_near(1).as_yoctonear())));
+        assert_eq!(contract.get_config().max_purchase, Some(U128(NearToken::from_near(1).as_yoctonear())));
+
+        // 5.01 NEAR: 5 NEAR purchase capped at 1 NEAR, 4 NEAR refunded on success
+        set_context(accounts(1), NearToken::from_millinear(5010), 0);
+        assert_eq!(contract.quote(U128(NearToken::from_millinear(5010).as_yoctonear())).0, U128(100));
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+        let tokens = contract.pending.get("s1").unwrap().tokens;
+        assert_eq!(tokens, 100);
+
+        set_context(accounts(5), NearToken::from_yoctonear(0), 0);
+        let message = contract.on_captcha_verified(
+            "s1".to_string(),
+            accounts(1),
+            NearToken::from_near(5),
+            U128(tokens),
+            NearToken::from_millinear(10),
+            None,
+            Ok(Some(near_sdk::serde_json::to_value(response(true, None)).unwrap())),
+        );
+
+        let excess = NearToken::from_near(4).as_yoctonear();
+        assert!(message.contains("bought 100 tokens"), "{}", message);
+        assert!(message.contains(&format!("Refunded {} yoctoNEAR", excess)), "{}", message);
+        assert_eq!(contract.get_metrics().near_raised, U128(NearToken::from_near(1).as_yoctonear()));
+    }
+
+    #[test]
+    fn max_near_per_tx_rejects_purchases_above_the_cap() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        let cap = NearToken::from_near(2).as_yoctonear();
+        contract.set_max_near_per_tx(Some(U128(cap)));
+        assert_eq!(contract.get_config().max_near_per_tx, Some(U128(cap)));
+
+        let reserve = contract.buyer_execution_reserve();
+        assert_eq!(contract.can_buy(accounts(1), U128(cap + reserve), None, None), None);
+        assert_eq!(
+            contract.can_buy(accounts(1), U128(cap + reserve + 1), None, None),
+            Some("Purchase of 2.000000000000000000000001 NEAR exceeds the per-transaction cap of 2 NEAR".to_string())
+        );
+
+        set_context(accounts(1), NearToken::from_yoctonear(cap + reserve), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+        assert!(contract.is_session_active("s1".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the per-transaction cap")]
+    fn buy_tokens_rejects_purchase_above_max_near_per_tx() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_max_near_per_tx(Some(U128(NearToken::from_near(2).as_yoctonear())));
+
+        set_context(accounts(1), NearToken::from_millinear(3010), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Maximum purchase must be at least the minimum purchase")]
+    fn max_purchase_below_min_is_rejected() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_max_purchase_near(Some(U128(1)));
+    }
+
+    /// Deposit and `payer_account_id` of the last `request_execution` call
+    #[cfg(not(feature = "mock-captcha"))]
+    fn last_outlayer_call() -> (u128, String) {
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight {
+                    method_name,
+                    args,
+                    attached_deposit,
+                    ..
+                } if method_name == b"request_execution" => {
+                    let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                    Some((attached_deposit.as_yoctonear(), args["payer_account_id"].as_str().unwrap().to_string()))
+                }
+                _ => None,
+            })
+            .next_back()
+            .expect("request_execution call")
+    }
+
+    #[cfg(not(feature = "mock-captcha"))]
+    #[test]
+    fn request_execution_gets_the_gas_floor() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_min_request_gas(U64(Gas::from_tgas(120).as_gas()));
+        assert_eq!(contract.get_config().min_request_gas, U64(Gas::from_tgas(120).as_gas()));
+
+        buy(&mut contract, accounts(1), "s1");
+        let (prepaid_gas, gas_weight) = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, prepaid_gas, gas_weight, .. }
+                    if method_name == b"request_execution" =>
+                {
+                    Some((prepaid_gas, gas_weight))
+                }
+                _ => None,
+            })
+            .expect("request_execution call");
+        assert_eq!(prepaid_gas, Gas::from_tgas(120));
+        assert_eq!(gas_weight.0, 1);
+    }
+
+    #[cfg(not(feature = "mock-captcha"))]
+    #[test]
+    #[should_panic(expected = "Not enough gas")]
+    fn buy_tokens_rejects_gas_below_floor() {
+        let mut contract = new_contract(1_000);
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(5))
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(NearToken::from_millinear(1010))
+            .prepaid_gas(Gas::from_tgas(100))
+            .build());
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Request gas leaves no room for the callback")]
+    fn min_request_gas_must_leave_room_for_callback() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_min_request_gas(U64(Gas::from_tgas(290).as_gas()));
+    }
+
+    #[cfg(feature = "mock-captcha")]
+    #[test]
+    fn mock_captcha_skips_outlayer() {
+        let mut contract = new_contract(1_000);
+        assert!(contract.get_config().mock_captcha);
+        buy(&mut contract, accounts(1), "s1");
+
+        let methods: Vec<Vec<u8>> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, .. } => Some(method_name),
+                _ => None,
+            })
+            .collect();
+        assert!(methods.contains(&b"mock_request_execution".to_vec()));
+        assert!(!methods.contains(&b"request_execution".to_vec()));
+
+        set_context(accounts(5), NearToken::from_yoctonear(0), 0);
+        let response = contract.mock_request_execution("s1".to_string());
+        settle(&mut contract, "s1", Ok(response));
+        assert_eq!(contract.get_purchased(accounts(1)), U128(100));
+    }
+
+    /// Buyers passed to `on_token_purchase` calls on `hook`
+    fn hook_calls(hook: &AccountId) -> Vec<(String, String)> {
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .filter(|receipt| &receipt.receiver_id == hook)
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, args, .. }
+                    if method_name == b"on_token_purchase" =>
+                {
+                    let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(&args).unwrap();
+                    Some((args["buyer"].as_str().unwrap().to_string(), args["tokens"].as_str().unwrap().to_string()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn post_purchase_hook_is_called_on_success_only() {
+        let hook = accounts(4);
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_post_purchase_hook(Some(hook.clone()));
+        assert_eq!(contract.get_config().post_purchase_hook, Some(hook.clone()));
+
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(false, Some("wrong_answer")))));
+        assert!(hook_calls(&hook).is_empty());
+
+        buy(&mut contract, accounts(1), "s2");
+        settle(&mut contract, "s2", Ok(Some(response(true, None))));
+        assert_eq!(hook_calls(&hook), vec![(accounts(1).to_string(), "100".to_string())]);
+    }
+
+    #[test]
+    fn post_purchase_hook_is_skipped_when_unset() {
+        let mut contract = new_contract(1_000);
+        buy(&mut contract, accounts(1), "s1");
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+        assert!(hook_calls(&accounts(4)).is_empty());
+    }
+
+    #[cfg(not(feature = "mock-captcha"))]
+    #[test]
+    fn buyer_pays_execution_by_default() {
+        let mut contract = new_contract(10_000);
+        buy(&mut contract, accounts(1), "s1");
+
+        // Only the execution fee goes to OutLayer; the purchase amount stays here
+        assert_eq!(last_outlayer_call(), (EXECUTION_RESERVE, accounts(1).to_string()));
+        let accounting = contract.get_execution_accounting();
+        assert!(!accounting.self_pays_execution);
+        assert_eq!(accounting.refund_recipient, "buyer");
+        assert_eq!(accounting.buyer_execution_fees, U128(EXECUTION_RESERVE));
+        assert_eq!(accounting.contract_execution_fees, U128(0));
+    }
+
+    #[cfg(not(feature = "mock-captcha"))]
+    #[test]
+    fn contract_can_pay_execution() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_self_pays_execution(true);
+        let yocto = |millinear: u128| U128(NearToken::from_millinear(millinear).as_yoctonear());
+
+        // No execution reserve: the whole deposit buys tokens
+        assert_eq!(contract.get_config().execution_reserve, U128(0));
+        assert_eq!(contract.get_min_deposit(), yocto(100));
+        assert_eq!(contract.quote(yocto(1000)), (U128(100), U128(0)));
+        assert_eq!(contract.quote(yocto(150)), (U128(15), U128(0)));
+        assert_eq!(contract.quote(yocto(99)), (U128(0), U128(0)));
+        assert_eq!(contract.get_required_deposit(U128(150)), yocto(1500));
+
+        set_context(accounts(1), NearToken::from_near(1), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+
+        assert_eq!(contract.pending.get("s1").unwrap().amount, NearToken::from_near(1).as_yoctonear());
+        assert_eq!(last_outlayer_call(), (EXECUTION_RESERVE, accounts(5).to_string()));
+        let accounting = contract.get_execution_accounting();
+        assert_eq!(accounting.refund_recipient, "contract");
+        assert_eq!(accounting.buyer_execution_fees, U128(0));
+        assert_eq!(accounting.contract_execution_fees, U128(EXECUTION_RESERVE));
+    }
+
+    #[test]
+    fn fractional_near_buys_proportional_tokens() {
+        assert_eq!(calculate_tokens(NearToken::from_millinear(1500).as_yoctonear(), 0), 150);
+        assert_eq!(calculate_tokens(NearToken::from_millinear(1990).as_yoctonear(), 0), 199);
+        assert_eq!(calculate_tokens(DEFAULT_MIN_PURCHASE, 0), 10);
+
+        let contract = new_contract(10_000);
+        let attached = NearToken::from_millinear(1510).as_yoctonear();
+        assert_eq!(contract.quote(U128(attached)).0, U128(150));
+    }
+
+    #[test]
+    fn can_buy_reports_why_a_purchase_would_fail() {
+        let mut contract = new_contract(150);
+        let yocto = |millinear: u128| U128(NearToken::from_millinear(millinear).as_yoctonear());
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("EARLY".to_string(), 1_000);
+
+        assert_eq!(contract.can_buy(accounts(1), yocto(1010), None, None), None);
+        assert_eq!(contract.can_buy(accounts(1), yocto(1010), Some("EARLY".to_string()), None), None);
+        assert!(contract.can_buy(accounts(1), yocto(100), None, None).unwrap().starts_with("Attach at least 0.11 NEAR"));
+        assert!(contract.can_buy(accounts(1), yocto(2010), None, None).unwrap().starts_with("Not enough tokens available"));
+        assert!(contract
+            .can_buy(accounts(1), yocto(1010), Some("NOPE".to_string()), None)
+            .unwrap()
+            .starts_with("Unknown discount code"));
+
+        contract.set_daily_cap(Some(U128(50)));
+        assert!(contract.can_buy(accounts(1), yocto(1010), None, None).unwrap().starts_with("Daily cap reached"));
+        contract.set_daily_cap(None);
+
+        contract.set_allowlist_enabled(true);
+        assert_eq!(
+            contract.can_buy(accounts(1), yocto(1010), None, None),
+            Some(format!("{} is not on the allowlist", accounts(1)))
+        );
+
+        // Same verdict as buy_tokens, which would now go through
+        contract.add_to_allowlist(vec![accounts(1)]);
+        assert_eq!(contract.can_buy(accounts(1), yocto(1010), None, None), None);
+        buy(&mut contract, accounts(1), "s1");
+        assert!(contract.is_session_active("s1".to_string()));
+    }
+
+    #[test]
+    fn discounted_price_includes_unused_discount() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_discount_code("EARLY".to_string(), 1_000);
+        contract.add_discount_code("QUARTER".to_string(), 25);
+
+        assert_eq!(contract.get_effective_price(None), "100 tokens per 1 NEAR");
+        assert_eq!(contract.get_effective_price(Some(accounts(1))), "100 tokens per 1 NEAR");
+        assert_eq!(contract.get_discounted_price(None, None), "100 tokens per 1 NEAR");
+        assert_eq!(contract.get_discounted_price(None, Some("EARLY".to_string())), "110 tokens per 1 NEAR");
+        assert_eq!(contract.get_discounted_price(None, Some("QUARTER".to_string())), "100.25 tokens per 1 NEAR");
+        assert_eq!(contract.get_discounted_price(None, Some("NOPE".to_string())), "100 tokens per 1 NEAR");
+
+        buy_with_code(&mut contract, accounts(1), "s1", "EARLY");
+        let price = |account| contract.get_discounted_price(Some(account), Some("EARLY".to_string()));
+        assert_eq!(price(accounts(1)), "100 tokens per 1 NEAR");
+        assert_eq!(price(accounts(2)), "110 tokens per 1 NEAR");
+    }
+
+    #[test]
+    fn near_amounts_are_formatted_without_trailing_zeros() {
+        assert_eq!(format_near(0), "0");
+        assert_eq!(format_near(NearToken::from_near(2).as_yoctonear()), "2");
+        assert_eq!(format_near(NearToken::from_millinear(110).as_yoctonear()), "0.11");
+        assert_eq!(format_near(1), "0.000000000000000000000001");
+        assert_eq!(new_contract(1_000).get_min_deposit(), U128(NearToken::from_millinear(110).as_yoctonear()));
+    }
+
+    #[test]
+    fn min_purchase_is_owner_configurable() {
+        let mut contract = new_contract(10_000);
+        let yocto = |millinear: u128| U128(NearToken::from_millinear(millinear).as_yoctonear());
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_min_purchase(yocto(1000));
+
+        assert_eq!(contract.get_config().min_purchase, yocto(1000));
+        assert_eq!(contract.get_min_deposit(), yocto(1010));
+        assert_eq!(contract.quote(yocto(1009)), (U128(0), U128(0)));
+        assert_eq!(contract.quote(yocto(1010)), (U128(100), yocto(10)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach at least 1.01 NEAR (1 NEAR minimum purchase + 0.01 NEAR for OutLayer execution)")]
+    fn buy_tokens_reports_live_min_purchase() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_min_purchase(U128(NearToken::from_near(1).as_yoctonear()));
+
+        set_context(accounts(1), NearToken::from_millinear(500), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Minimum purchase must be positive")]
+    fn set_min_purchase_rejects_zero() {
+        let mut contract = new_contract(10_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_min_purchase(U128(0));
+    }
+
+    #[test]
+    fn sale_id_defaults_to_contract_account_and_tags_events() {
+        let mut contract = new_contract(1_000);
+        assert_eq!(contract.get_sale_id(), accounts(5).to_string());
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.increase_supply(U128(10));
+        let event = near_sdk::test_utils::get_logs().pop().unwrap();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(event.strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+        assert_eq!(event["data"][0]["sale_id"], accounts(5).to_string());
+
+        let contract = TokenSaleContract::new(
+            accounts(0),
+            U128(1_000),
+            LAUNCHPAD_URL.to_string(),
+            Some("seed-round".to_string()),
+            None,
+        );
+        assert_eq!(contract.get_sale_id(), "seed-round");
+    }
+
+    #[test]
+    #[should_panic(expected = "Sale ID must be 1 to 64 bytes")]
+    fn empty_sale_id_is_rejected() {
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        TokenSaleContract::new(accounts(0), U128(1_000), LAUNCHPAD_URL.to_string(), Some(String::new()), None);
+    }
+
+    #[test]
+    fn token_amounts_are_in_smallest_unit_everywhere() {
+        // A fresh buyer each round: the mocked storage outlives each contract
+        for (buyer, decimals) in [(accounts(1), 6u8), (accounts(2), 18), (accounts(3), 24)] {
+            let unit = 10u128.pow(decimals as u32);
+            let hook = accounts(4);
+            set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+            let mut contract =
+                TokenSaleContract::new(accounts(0), U128(10_000 * unit), LAUNCHPAD_URL.to_string(), None, Some(decimals));
+            contract.set_post_purchase_hook(Some(hook.clone()));
+            assert_eq!(contract.get_config().token_decimals, decimals);
+
+            let credited = 100 * unit;
+            assert_eq!(contract.quote(U128(NearToken::from_millinear(1010).as_yoctonear())).0, U128(credited));
+            buy(&mut contract, buyer.clone(), buyer.as_str());
+            let message = settle(&mut contract, buyer.as_str(), Ok(Some(response(true, None))));
+
+            assert!(message.starts_with(&format!("Success! You bought {} tokens", credited)), "{}", message);
+            assert_eq!(contract.get_purchased(buyer.clone()), U128(credited));
+            assert_eq!(hook_calls(&hook), vec![(buyer.to_string(), credited.to_string())]);
+            assert_eq!(contract.get_history(buyer.clone(), 0, 1)[0].tokens, U128(credited));
+            assert_eq!(contract.get_stats(), (U128(credited), U128(10_000 * unit)));
+            assert!(near_sdk::test_utils::get_logs()
+                .iter()
+                .any(|log| log.contains(&format!(r#""tokens":"{}""#, credited))));
+            // Only the price is quoted in whole tokens
+            assert_eq!(contract.get_config().tokens_per_near, 100);
+        }
+    }
+
+    #[test]
+    fn fractional_tokens_are_sold_with_decimals() {
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        let mut contract =
+            TokenSaleContract::new(accounts(0), U128(10_000_000_000), LAUNCHPAD_URL.to_string(), None, Some(6));
+
+        // 1.01234567 NEAR attached, 0.01 NEAR of it for execution: 100.234567 tokens
+        let attached = 1_012_345_670_000_000_000_000_000;
+        assert_eq!(contract.quote(U128(attached)).0, U128(100_234_567));
+        set_context(accounts(5), NearToken::from_yoctonear(attached), 0);
+        let _ = contract.buy_tokens("s1".to_string(), None, None, None, None, None, None, None);
+        settle(&mut contract, "s1", Ok(Some(response(true, None))));
+
+        assert_eq!(contract.get_purchased(accounts(5)), U128(100_234_567));
+        assert_eq!(contract.get_stats().0, U128(100_234_567));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token decimals must be at most 24")]
+    fn too_many_token_decimals_are_rejected() {
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        TokenSaleContract::new(accounts(0), U128(1_000), LAUNCHPAD_URL.to_string(), None, Some(25));
+    }
+
+    #[test]
+    fn worker_input_serializes_in_field_order() {
+        let buyer: AccountId = "bob.testnet".parse().unwrap();
+        let input = WorkerInput {
+            session_id: "s1",
+            sale_id: "sale-1",
+            buyer: &buyer,
+            amount: U128(1_990_000_000_000_000_000_000_000),
+            expected_tokens: U128(198),
+            launchpad_url: LAUNCHPAD_URL,
+            poll_interval_ms: Some(500),
+            answer: None,
+            difficulty: None,
+            client_ip: None,
+            user_agent: None,
+        };
+
+        assert_eq!(
+            near_sdk::serde_json::to_string(&input).unwrap(),
+            r#"{"session_id":"s1","sale_id":"sale-1","buyer":"bob.testnet","amount":"1990000000000000000000000","expected_tokens":"198","launchpad_url":"https://launchpad.example","poll_interval_ms":500}"#
+        );
+
+        let input = WorkerInput {
+            difficulty: Some(2),
+            client_ip: Some("203.0.113.7"),
+            user_agent: Some("Mozilla/5.0"),
+            ..input
+        };
+        assert_eq!(
+            near_sdk::serde_json::to_string(&input).unwrap(),
+            r#"{"session_id":"s1","sale_id":"sale-1","buyer":"bob.testnet","amount":"1990000000000000000000000","expected_tokens":"198","launchpad_url":"https://launchpad.example","poll_interval_ms":500,"difficulty":2,"client_ip":"203.0.113.7","user_agent":"Mozilla/5.0"}"#
+        );
+    }
+
+    #[test]
+    fn allowlist_gates_purchases_when_enabled() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_to_allowlist(vec![accounts(1)]);
+
+        // Disabled by default: anyone may buy
+        buy(&mut contract, accounts(2), "s1");
+        assert!(contract.is_session_active("s1".to_string()));
+
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        assert!(contract.get_config().allowlist_enabled);
+        assert!(contract.is_allowlisted(accounts(1)));
+        assert!(!contract.is_allowlisted(accounts(2)));
+
+        buy(&mut contract, accounts(1), "s2");
+        assert!(contract.is_session_active("s2".to_string()));
+    }
+
+    #[test]
+    fn whitelist_names_drive_the_allowlist() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_whitelist_enabled(true);
+        contract.whitelist_add(accounts(1));
+        contract.whitelist_add_batch(vec![accounts(2), accounts(3)]);
+        contract.whitelist_remove(accounts(3));
+        contract.whitelist_remove_batch(vec![accounts(2)]);
+
+        assert!(contract.get_config().allowlist_enabled);
+        assert!(contract.is_whitelisted(accounts(1)));
+        assert!(!contract.is_whitelisted(accounts(2)));
+        assert_eq!(contract.get_allowlist(0, 10), vec![accounts(1)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not on the allowlist")]
+    fn allowlist_rejects_unlisted_buyer() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        buy(&mut contract, accounts(2), "s1");
+    }
+
+    #[test]
+    fn blacklist_blocks_purchases_until_removed() {
+        let mut contract = new_contract(1_000);
+        let yocto = |millinear: u128| U128(NearToken::from_millinear(millinear).as_yoctonear());
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_to_blacklist(vec![accounts(1), accounts(2)]);
+        assert!(contract.is_blacklisted(accounts(1)));
+        assert!(!contract.is_blacklisted(accounts(3)));
+        assert_eq!(contract.get_blacklist(1, 10), vec![accounts(2)]);
+        assert_eq!(contract.can_buy(accounts(1), yocto(1010), None, None), Some("Account is blacklisted".to_string()));
+
+        // Allowlisting doesn't override the blacklist
+        contract.add_to_allowlist(vec![accounts(1)]);
+        contract.set_allowlist_enabled(true);
+        assert_eq!(contract.can_buy(accounts(1), yocto(1010), None, None), Some("Account is blacklisted".to_string()));
+
+        contract.remove_from_blacklist(vec![accounts(1)]);
+        assert_eq!(contract.get_blacklist(0, 10), vec![accounts(2)]);
+        buy(&mut contract, accounts(1), "s1");
+        assert!(contract.is_session_active("s1".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is blacklisted")]
+    fn blacklisted_buyer_is_rejected_before_outlayer() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.add_to_blacklist(vec![accounts(1)]);
+        buy(&mut contract, accounts(1), "s1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner")]
+    fn blacklist_is_owner_only() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        contract.add_to_blacklist(vec![accounts(2)]);
+    }
+
+    /// Merkle tree over `accounts(1..=4)`: returns the hex root and each leaf's proof
+    fn allowlist_tree() -> (String, Vec<Vec<String>>) {
+        let leaves: Vec<[u8; 32]> = (1..=4).map(|i| env::sha256_array(accounts(i).as_bytes())).collect();
+        let left = merkle_parent(&leaves[0], &leaves[1]);
+        let right = merkle_parent(&leaves[2], &leaves[3]);
+        let proofs = [
+            [leaves[1], right],
+            [leaves[0], right],
+            [leaves[3], left],
+            [leaves[2], left],
+        ];
+        let proofs = proofs
+            .iter()
+            .map(|proof| proof.iter().map(hash_to_hex).collect())
+            .collect();
+        (hash_to_hex(&merkle_parent(&left, &right)), proofs)
+    }
+
+    fn buy_with_proof(contract: &mut TokenSaleContract, buyer: AccountId, session_id: &str, proof: Vec<String>) {
+        set_context(buyer, NearToken::from_millinear(1010), 0);
+        let _ = contract.buy_tokens(session_id.to_string(), None, None, None, Some(proof), None, None, None);
+    }
+
+    #[test]
+    fn allowlist_accepts_valid_merkle_proofs() {
+        let (root, proofs) = allowlist_tree();
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        contract.set_allowlist_root(Some(root.clone()));
+        assert_eq!(contract.get_allowlist_root(), Some(root));
+
+        for (i, proof) in proofs.into_iter().enumerate() {
+            let session_id = format!("s{}", i);
+            buy_with_proof(&mut contract, accounts(i + 1), &session_id, proof);
+            assert!(contract.is_session_active(session_id));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is not on the allowlist")]
+    fn allowlist_rejects_proof_for_another_account() {
+        let (root, proofs) = allowlist_tree();
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        contract.set_allowlist_root(Some(root));
+
+        // accounts(1)'s proof doesn't verify accounts(5)
+        buy_with_proof(&mut contract, accounts(5), "s1", proofs[0].clone());
+    }
+
+    #[test]
+    #[should_panic(expected = "is not on the allowlist")]
+    fn allowlist_rejects_tampered_proof() {
+        let (root, mut proofs) = allowlist_tree();
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        contract.set_allowlist_root(Some(root));
+
+        proofs[1].pop();
+        buy_with_proof(&mut contract, accounts(2), "s1", proofs[1].clone());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner")]
+    fn set_launchpad_url_is_owner_only() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(1), NearToken::from_yoctonear(0), 0);
+        contract.set_launchpad_url("https://evil.example".to_string());
+    }
+
+    #[test]
+    fn malformed_proof_is_reported_by_can_buy() {
+        let (root, _) = allowlist_tree();
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        contract.set_allowlist_root(Some(root));
+
+        let reason = contract.can_buy(
+            accounts(2),
+            U128(NearToken::from_millinear(1010).as_yoctonear()),
+            None,
+            Some(vec!["not hex".to_string()]),
+        );
+
+        assert_eq!(reason.as_deref(), Some(r#"Invalid Merkle proof hash "not hex""#));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid Merkle proof hash")]
+    fn malformed_proof_rejects_the_purchase() {
+        let (root, _) = allowlist_tree();
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_enabled(true);
+        contract.set_allowlist_root(Some(root));
+
+        buy_with_proof(&mut contract, accounts(2), "s1", vec!["not hex".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Allowlist root must be 32 bytes of hex")]
+    fn allowlist_root_must_be_a_hash() {
+        let mut contract = new_contract(1_000);
+        set_context(accounts(0), NearToken::from_yoctonear(0), 0);
+        contract.set_allowlist_root(Some("abcd".to_string()));
+    }
+
+    #[test]
+    fn allowlist_is_paginated() {
+        let mut contract = new_contract(10_000);
+        contract.add_to_allowlist(vec![accounts(1), accounts(2), accounts(3)]);
+
+        assert_eq!(contract.get_allowlist(0, 2), vec![accounts(1), accounts(2)]);
+        assert_eq!(contract.get_allowlist(2, 2), vec![accounts(3)]);
+        assert!(contract.get_allowlist(5, 2).is_empty());
+    }
 }